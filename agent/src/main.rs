@@ -1,38 +1,550 @@
-use clap::Parser;
-use futures_util::{SinkExt, StreamExt};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+use clap::{Parser, Subcommand, ValueEnum};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use tokio_tungstenite::{
+    connect_async_tls_with_config, connect_async_with_config, Connector,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{HeaderName, HeaderValue},
+        protocol::{frame::coding::CloseCode, CloseFrame, Message, WebSocketConfig},
+        error::TlsError,
+        Error as WsError,
+    },
+};
 use url::Url;
-use tracing::{info, error, warn};
+use tracing::{info, info_span, error, warn, Instrument};
 use serde::{Serialize, Deserialize};
-use std::{env, time::Duration, sync::Arc};
-use tokio::{time::sleep, sync::broadcast};
-use std::str::FromStr;
+use std::{collections::{BTreeMap, HashMap}, env, str::FromStr, time::Duration, sync::{Arc, Mutex}};
+use tokio::{time::{sleep, timeout_at, Instant}, sync::{broadcast, mpsc, Semaphore}, task::{AbortHandle, JoinSet}};
+use rand::Rng;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::io::{Read, Write};
 
-const MAX_RETRIES: u32 = 10;
-const INITIAL_RETRY_DELAY_MS: u64 = 1000;
-const MAX_RETRY_DELAY_MS: u64 = 30000;
+// Each retry delay is randomly stretched or shrunk by up to this fraction
+const RETRY_JITTER: f64 = 0.2;
 const PING_INTERVAL_SECS: u64 = 30;
 const GATEWAY_UNREACHABLE_EXIT_CODE: i32 = 1;
 const SHUTDOWN_EXIT_CODE: i32 = 0;
 const LOCAL_APP_URL: &str = "http://127.0.0.1:8000";
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+// Replies (responses and body chunks) queued for the gateway before request tasks wait for the
+// connection to catch up
+const REPLY_QUEUE_CAPACITY: usize = 16;
+const LOCAL_HEALTH_TIMEOUT_SECS: u64 = 5;
+// How long --check waits for the gateway to confirm the handshake
+const CHECK_TIMEOUT_SECS: u64 = 10;
+// Delay before the first retry of a failed local GET or HEAD, doubled for each further retry up
+// to 16s
+const LOCAL_RETRY_DELAY_MS: u64 = 250;
+// Under --ws-compress, payloads smaller than this are sent as they are: gzip and base64 would
+// barely shrink them
+const COMPRESS_MIN_SIZE: usize = 1024;
+// The only compression the agent asks for in its handshake
+const COMPRESSION_GZIP: &str = "gzip";
+const SUPPORTED_METHODS: [reqwest::Method; 7] = [
+    reqwest::Method::GET,
+    reqwest::Method::POST,
+    reqwest::Method::PUT,
+    reqwest::Method::DELETE,
+    reqwest::Method::HEAD,
+    reqwest::Method::PATCH,
+    reqwest::Method::OPTIONS,
+];
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(long, required = true)]
-    tunnel_id: String,
+    /// Tunnel ID in the form agent_{uuid}_{purpose} (see generate-id), optionally followed by =URL of the
+    /// local app it fronts (default http://127.0.0.1:8000); repeat to serve several tunnels, each over its own connection
+    #[arg(long = "tunnel-id", required = true, value_parser = parse_tunnel)]
+    tunnels: Vec<Tunnel>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Gateway base URL, e.g. ws://gateway:3000; repeat or comma-separate to fail over in order
+    #[arg(long = "gateway-url", env = "GATEWAY_URL", value_delimiter = ',', default_value = "ws://127.0.0.1:3000", value_parser = parse_gateway_url)]
+    gateway_urls: Vec<Url>,
+
+    /// Path of the gateway's agent WebSocket endpoint, appended to each gateway URL (the gateway's --ws-path)
+    #[arg(long, env = "GATEWAY_WS_PATH", default_value = "/ws", value_parser = parse_ws_path)]
+    ws_path: String,
+
+    /// Shared secret presented to the gateway during the handshake
+    #[arg(long, env = "TUNNEL_TOKEN", hide_env_values = true)]
+    auth_token: Option<String>,
+
+    /// Route a path prefix to a local service, e.g. /api=http://127.0.0.1:8000 (repeatable)
+    #[arg(long = "route", value_parser = parse_route)]
+    routes: Vec<Route>,
+
+    /// Only accept this HTTP method, e.g. GET (repeat or comma-separate; every method when unset). The gateway answers others with 405
+    #[arg(long = "allow-method", value_delimiter = ',', value_parser = parse_allowed_method)]
+    allowed_methods: Vec<String>,
+
+    /// Only accept paths matching this pattern, e.g. /api/* (a trailing * matches the rest; repeatable; every path when unset). The gateway answers others with 404
+    #[arg(long = "allow-path", value_parser = parse_path_pattern)]
+    allowed_paths: Vec<String>,
+
+    /// Label reported to the gateway, e.g. env=staging (repeatable); clients can route by it
+    #[arg(long = "label", value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+
+    /// Stream local responses larger than this many bytes to the gateway in chunks
+    #[arg(long, default_value_t = 1024 * 1024)]
+    stream_threshold: u64,
+
+    /// Largest local response body relayed to the gateway, in bytes; bigger ones are answered with an error
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    max_response_size: u64,
+
+    /// Seconds to wait for the local app to answer a forwarded request (kept below the gateway's timeout)
+    #[arg(long, default_value_t = 25, value_parser = clap::value_parser!(u64).range(1..))]
+    local_timeout: u64,
+
+    /// Times a GET or HEAD is retried, with backoff, when the local app refuses the connection (e.g. while it restarts), within --local-timeout
+    #[arg(long, default_value_t = 2)]
+    local_retries: u32,
+
+    /// Accept any certificate from https:// local apps, e.g. self-signed dev servers. Insecure: never use against untrusted networks
+    #[arg(long)]
+    local_insecure: bool,
+
+    /// Host header sent to the local app instead of its URL's host, e.g. app.test for a virtual host
+    #[arg(long, value_parser = parse_host_header)]
+    host_header: Option<String>,
+
+    /// Send the client's Host header when the gateway forwards it, instead of the local app's host
+    #[arg(long, conflicts_with = "host_header")]
+    preserve_host: bool,
+
+    /// Path probed on the local app before each handshake, e.g. /health (no probe when unset)
+    #[arg(long)]
+    local_health_path: Option<String>,
+
+    /// Status code the health probe must return (any 2xx when unset)
+    #[arg(long)]
+    local_health_expect_status: Option<u16>,
+
+    /// Seconds between health re-probes while connected, reporting changes to the gateway (0 disables)
+    #[arg(long, default_value_t = 30)]
+    local_health_interval: u64,
+
+    /// Consecutive failed connection attempts before giving up (0 retries forever)
+    #[arg(long, default_value_t = 10)]
+    max_retries: u32,
+
+    /// Base reconnect delay in milliseconds, doubled after each failure
+    #[arg(long = "initial-retry-ms", default_value_t = 1000)]
+    initial_retry_delay_ms: u64,
+
+    /// Upper bound on the reconnect delay in milliseconds
+    #[arg(long = "max-retry-ms", default_value_t = 30000)]
+    max_retry_delay_ms: u64,
+
+    /// Largest WebSocket message or frame accepted from the gateway, in bytes
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    max_message_size: usize,
+
+    /// Gzip large responses inside messages, and ask the gateway to gzip large requests (a protocol extension, not permessage-deflate), if it was started with --ws-compress too
+    #[arg(long)]
+    ws_compress: bool,
+
+    /// Seconds to wait for the gateway's welcome after the handshake before reconnecting (0 waits forever)
+    #[arg(long, default_value_t = 10)]
+    welcome_timeout: u64,
+
+    /// Forwarded requests handled at once; more wait for a free slot. Values above 1 need a gateway that matches replies by request ID
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    concurrency: u32,
+
+    /// Answer every request with a description of itself instead of calling the local app
+    #[arg(long)]
+    echo: bool,
+
+    /// Connect to each gateway, complete the handshake and exit: 0 if every gateway accepted it, 1 otherwise
+    #[arg(long)]
+    check: bool,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+impl Args {
+    // The tunnel this connection serves; each tunnel's connection loop gets args narrowed to it
+    fn tunnel(&self) -> &Tunnel {
+        &self.tunnels[0]
+    }
+
+    fn for_tunnel(&self, tunnel: &Tunnel) -> Args {
+        Args {
+            tunnels: vec![tunnel.clone()],
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Print a new tunnel ID in the format the gateway accepts
+    GenerateId {
+        /// Last segment of the tunnel ID, e.g. web (letters and digits only)
+        #[arg(long, value_parser = parse_purpose)]
+        purpose: String,
+    },
+}
+
+// Parse a --host-header value, which must be a bare host with an optional port
+fn parse_host_header(value: &str) -> Result<String, String> {
+    match Url::parse(&format!("http://{}", value)) {
+        Ok(url) if url.host_str().is_some() && !value.contains(['/', '@', '?', '#']) => Ok(value.to_string()),
+        _ => Err(format!("expected a host such as app.test or app.test:8080, got '{}'", value)),
+    }
+}
+
+// Parse a tunnel purpose; the gateway splits tunnel IDs on '_', so only alphanumerics are allowed
+fn parse_purpose(value: &str) -> Result<String, String> {
+    if value.is_empty() || !value.chars().all(char::is_alphanumeric) {
+        return Err(format!("purpose must be non-empty letters and digits, got '{}'", value));
+    }
+    Ok(value.to_string())
+}
+
+// Check a tunnel ID against the format the gateway enforces, so a typo fails at startup
+// instead of being rejected on every reconnect
+fn parse_tunnel_id(value: &str) -> Result<String, String> {
+    let invalid = || format!("expected agent_{{uuid}}_{{purpose}}, got '{}'", value);
+    let mut parts = value.splitn(3, '_');
+    if parts.next() != Some("agent") {
+        return Err(invalid());
+    }
+    let uuid = parts.next().ok_or_else(invalid)?;
+    uuid::Uuid::parse_str(uuid).map_err(|_| invalid())?;
+    parse_purpose(parts.next().ok_or_else(invalid)?)?;
+    Ok(value.to_string())
+}
+
+// A --tunnel-id and the local app serving the paths no --route matches
+#[derive(Debug, Clone)]
+struct Tunnel {
+    id: String,
+    local_url: String,
+}
+
+// Parse a `--tunnel-id ID[=URL]` value
+fn parse_tunnel(value: &str) -> Result<Tunnel, String> {
+    let (id, local_url) = value.split_once('=').unwrap_or((value, LOCAL_APP_URL));
+    let url = Url::parse(local_url).map_err(|e| format!("invalid local URL '{}': {}", local_url, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("local URL must be an http:// or https:// URL, got '{}'", local_url));
+    }
+    Ok(Tunnel {
+        id: parse_tunnel_id(id)?,
+        local_url: local_url.trim_end_matches('/').to_string(),
+    })
+}
+
+// Parse a gateway base URL; --ws-path is appended to it when connecting
+fn parse_gateway_url(value: &str) -> Result<Url, String> {
+    Url::parse(value.trim_end_matches('/')).map_err(|e| format!("invalid gateway URL '{}': {}", value, e))
+}
+
+// Parse a --ws-path: a path such as /ws or /tunnel/connect, stored without a trailing slash
+fn parse_ws_path(value: &str) -> Result<String, String> {
+    let path = value.trim().trim_end_matches('/');
+    if !path.starts_with('/') || path.len() == 1 || path.contains(['?', '#']) {
+        return Err(format!("expected a path such as /ws, got '{}'", value));
+    }
+    Ok(path.to_string())
+}
+
+// URL of a gateway's agent WebSocket endpoint: its base URL (including any path, such as the
+// gateway's --route-prefix) followed by --ws-path
+fn gateway_ws_url(base: &Url, ws_path: &str) -> String {
+    format!("{}{}", base.as_str().trim_end_matches('/'), ws_path)
+}
+
+// Build a tunnel ID the gateway's validate_tunnel_id accepts
+fn generate_tunnel_id(purpose: &str) -> String {
+    format!("agent_{}_{}", uuid::Uuid::new_v4(), purpose)
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+// A path prefix mapped to a local target service
+#[derive(Debug, Clone)]
+struct Route {
+    prefix: String,
+    target: String,
+}
+
+// Parse a `--route PREFIX=URL` mapping
+fn parse_route(value: &str) -> Result<Route, String> {
+    let (prefix, target) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected PREFIX=URL, got '{}'", value))?;
+
+    if !prefix.starts_with('/') {
+        return Err(format!("route prefix must start with '/', got '{}'", prefix));
+    }
+    let url = Url::parse(target).map_err(|e| format!("invalid route target '{}': {}", target, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("route target must be an http:// or https:// URL, got '{}'", target));
+    }
+
+    let prefix = match prefix.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    };
+    Ok(Route {
+        prefix: prefix.to_string(),
+        target: target.trim_end_matches('/').to_string(),
+    })
+}
+
+// Parse a `--label KEY=VALUE` pair. The gateway matches `KEY:VALUE` query parameters
+// against labels, so keys can't contain a colon
+fn parse_label(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", value))?;
+    if key.is_empty() || key.contains(':') {
+        return Err(format!("label key must be non-empty and contain no ':', got '{}'", key));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+// Parse an --allow-method value, which must be a method the agent forwards
+fn parse_allowed_method(value: &str) -> Result<String, String> {
+    let method = value.trim().to_ascii_uppercase();
+    if !SUPPORTED_METHODS.iter().any(|supported| supported.as_str() == method) {
+        return Err(format!("expected one of GET, POST, PUT, DELETE, HEAD, PATCH or OPTIONS, got '{}'", value));
+    }
+    Ok(method)
+}
+
+// Parse an --allow-path pattern: a path, optionally ending in '*' to match every path starting
+// with the rest
+fn parse_path_pattern(value: &str) -> Result<String, String> {
+    let prefix = value.strip_suffix('*').unwrap_or(value);
+    if !value.starts_with('/') || prefix.contains(['*', '?', '#']) {
+        return Err(format!("expected a path such as /health or /api/*, got '{}'", value));
+    }
+    Ok(value.to_string())
+}
+
+// Refuse a request outside --allow-method and --allow-path. The gateway already holds these back
+// once they are in the handshake, so this only catches gateways too old to check
+fn check_allowed(args: &Args, method: &str, path: &str) -> Result<(), String> {
+    if !args.allowed_methods.is_empty() && !args.allowed_methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method)) {
+        return Err(format!("Method {} is not accepted by this agent (--allow-method)", method));
+    }
+    let path = path.split('?').next().unwrap_or_default();
+    let matches = |pattern: &String| match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    };
+    if !args.allowed_paths.is_empty() && !args.allowed_paths.iter().any(matches) {
+        return Err(format!("Path {} is not served by this agent (--allow-path)", path));
+    }
+    Ok(())
+}
+
+// Resolve the local URL for a request path, using the longest matching route prefix
+// (with the prefix stripped) and falling back to the tunnel's local app
+fn resolve_local_url(args: &Args, path: &str) -> String {
+    let matched = args.routes
+        .iter()
+        .filter_map(|route| {
+            let rest = path.strip_prefix(route.prefix.as_str())?;
+            // Only match on segment boundaries so /api doesn't capture /apix
+            if route.prefix == "/" || rest.is_empty() || rest.starts_with('/') || rest.starts_with('?') {
+                Some((route, rest))
+            } else {
+                None
+            }
+        })
+        .max_by_key(|(route, _)| route.prefix.len());
+
+    match matched {
+        Some((route, rest)) => format!("{}/{}", route.target, rest.trim_start_matches('/')),
+        None => format!("{}{}", args.tunnel().local_url, path),
+    }
+}
+
+// Host header for a local request: --host-header, or with --preserve-host the client's own. None lets
+// the HTTP client use the local URL's host, which is what virtual-host routing local apps expect
+fn local_host_header(args: &Args, headers: &[(String, String)]) -> Option<String> {
+    if args.preserve_host {
+        return headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("host"))
+            .map(|(_, value)| value.clone());
+    }
+    args.host_header.clone()
+}
+
+// Refuse a request path with dot segments (plain or percent-encoded), which would climb out of
+// the route prefix it matched once the local URL is normalized, e.g. /api/../admin
+fn validate_request_path(path: &str) -> Result<(), String> {
+    let path = path.split('?').next().unwrap_or_default();
+    let climbs = path
+        .split('/')
+        .any(|segment| matches!(segment.to_ascii_lowercase().replace("%2e", ".").as_str(), "." | ".."));
+    if climbs {
+        return Err(format!("Refusing path that escapes its route prefix: {}", path));
+    }
+    Ok(())
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct AgentHandshake {
     tunnel_id: String,
     agent_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth_token: Option<String>,
+    // Result of the local health probe; the gateway does not route to unhealthy agents
+    local_healthy: bool,
+    // Connection ID from before the last reconnect, so the gateway can link the two
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_connection_id: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+    // What this agent fronts, shown to operators on the gateway's /connections
+    local_url: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    routes: BTreeMap<String, String>,
+    // Requests this agent handles at once; the gateway keeps dispatching until that many are open
+    concurrency: u32,
+    // Methods and path patterns this agent serves, so the gateway refuses the rest itself
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    allowed_methods: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    allowed_paths: Vec<String>,
+    // "gzip" under --ws-compress
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct GatewayMessage {
     message_type: String,
     payload: String,
+    // Position of a "response_chunk" within a streamed body, or the chunk count on "response_end"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
+    // Position of the message among all those the agent sent on this connection, for diagnostics
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message_seq: Option<u64>,
+    // The forwarded request a "response", "response_chunk", "response_end" or "error" answers, so
+    // the gateway can tell apart replies to requests handled concurrently
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    // The payload is gzipped and base64 encoded (--ws-compress)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    compressed: bool,
+}
+
+impl GatewayMessage {
+    fn new(message_type: &str, payload: String) -> Self {
+        GatewayMessage {
+            message_type: message_type.to_string(),
+            payload,
+            sequence: None,
+            message_seq: None,
+            request_id: None,
+            compressed: false,
+        }
+    }
+
+    // A reply to the forwarded request with this ID
+    fn reply(message_type: &str, payload: String, request_id: Option<&str>) -> Self {
+        GatewayMessage {
+            request_id: request_id.map(str::to_string),
+            ..GatewayMessage::new(message_type, payload)
+        }
+    }
+
+    // Gzip the payload if it is large enough to be worth it
+    fn compress(mut self) -> Self {
+        if self.payload.len() < COMPRESS_MIN_SIZE {
+            return self;
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        if let Ok(gzipped) = encoder.write_all(self.payload.as_bytes()).and_then(|()| encoder.finish()) {
+            self.payload = BASE64.encode(gzipped);
+            self.compressed = true;
+        }
+        self
+    }
+
+    // Restore a compressed payload, refusing one that inflates beyond `limit` bytes
+    fn decompress(mut self, limit: usize) -> Result<Self, String> {
+        if !self.compressed {
+            return Ok(self);
+        }
+        let gzipped = BASE64.decode(self.payload.as_bytes()).map_err(|e| format!("invalid base64: {}", e))?;
+        let mut payload = String::new();
+        GzDecoder::new(gzipped.as_slice())
+            .take(limit as u64 + 1)
+            .read_to_string(&mut payload)
+            .map_err(|e| format!("invalid gzip payload: {}", e))?;
+        if payload.len() > limit {
+            return Err(format!("payload inflates beyond the {} byte limit", limit));
+        }
+        self.payload = payload;
+        self.compressed = false;
+        Ok(self)
+    }
+}
+
+// Parse a message from the gateway, restoring its payload if it was compressed
+fn parse_gateway_message(text: &str, max_message_size: usize) -> Option<GatewayMessage> {
+    let msg = serde_json::from_str::<GatewayMessage>(text).ok()?;
+    match msg.decompress(max_message_size) {
+        Ok(msg) => Some(msg),
+        Err(e) => {
+            warn!("Dropping compressed message from gateway: {}", e);
+            None
+        }
+    }
+}
+
+// The connection's write half. Messages are numbered as they are written, so the numbers follow
+// the order on the wire and the gateway can spot dropped or reordered ones
+struct GatewayWriter<W> {
+    sink: W,
+    next_seq: u64,
+    // Set once the gateway's welcome confirms it takes compressed responses
+    compress: bool,
+}
+
+impl<W: Sink<Message, Error = WsError> + Unpin> GatewayWriter<W> {
+    fn new(sink: W) -> Self {
+        GatewayWriter { sink, next_seq: 0, compress: false }
+    }
+
+    // Send a protocol message with the next number in the sequence
+    async fn send(&mut self, mut msg: GatewayMessage) -> Result<(), WsError> {
+        if self.compress && matches!(msg.message_type.as_str(), "response" | "response_chunk") {
+            msg = msg.compress();
+        }
+        msg.message_seq = Some(self.next_seq);
+        let text = serde_json::to_string(&msg).map_err(|e| WsError::Io(e.into()))?;
+        self.sink.send(Message::Text(text)).await?;
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    // Send a frame outside the sequence: the handshake, pings, pongs and the close
+    async fn send_frame(&mut self, frame: Message) -> Result<(), WsError> {
+        self.sink.send(frame).await
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -40,7 +552,19 @@ struct ForwardedRequest {
     method: String,
     path: String,
     body: String,
+    // The body is base64 (multipart uploads and other non-text bodies), sent on as raw bytes
+    #[serde(default)]
+    binary: bool,
     headers: Vec<(String, String)>,
+    // The client sent the body chunked, so it's sent to the local app without a Content-Length too
+    #[serde(default)]
+    chunked: bool,
+    // Trailer fields that followed the client's body
+    #[serde(default)]
+    trailers: Vec<(String, String)>,
+    // Named by the gateway's "cancel" message when it stops waiting for the reply
+    #[serde(default)]
+    request_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -61,43 +585,180 @@ impl std::fmt::Display for AgentError {
 
 impl std::error::Error for AgentError {}
 
-async fn handle_forwarded_request(request: ForwardedRequest) -> Result<String, Box<dyn std::error::Error>> {
+// Payload of "welcome": identifies this connection on the gateway
+#[derive(Debug, Deserialize)]
+struct Welcome {
+    connection_id: String,
+    server_version: String,
+    // "gzip" when the gateway runs with --ws-compress
+    #[serde(default)]
+    compression: Option<String>,
+}
+
+// Payload of "ws_open": open a WebSocket to the local app for this stream
+#[derive(Debug, Serialize, Deserialize)]
+struct TunnelOpen {
+    stream_id: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+// Payload of "ws_frame": one data frame relayed in either direction (binary frames are base64)
+#[derive(Debug, Serialize, Deserialize)]
+struct TunnelFrame {
+    stream_id: String,
+    data: String,
+    #[serde(default)]
+    binary: bool,
+}
+
+// Payload of "ws_close": either side finished the stream
+#[derive(Debug, Serialize, Deserialize)]
+struct TunnelClose {
+    stream_id: String,
+    #[serde(default)]
+    code: Option<u16>,
+    #[serde(default)]
+    reason: String,
+}
+
+// Frames from the gateway destined for each open tunneled WebSocket, keyed by stream ID
+type TunnelMap = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Message>>>>;
+
+// A local response ready to be relayed to the gateway
+enum LocalResponse {
+    // Serialized AgentResponse with the body inline
+    Buffered(String),
+    // Serialized AgentResponse head (data.streamed = true); the body follows in chunks, and must
+    // arrive by the deadline unless it is an event stream
+    Streamed { head: String, body: reqwest::Response, deadline: Instant, event_stream: bool },
+}
+
+// Whether a Content-Type value denotes JSON (application/json or a +json suffix type)
+fn is_json_content_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    mime == "application/json" || mime.ends_with("+json")
+}
+
+// Whether a Content-Type value denotes Server-Sent Events, whose body lasts as long as the local
+// app keeps sending
+fn is_event_stream_content_type(content_type: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .eq_ignore_ascii_case("text/event-stream")
+}
+
+async fn handle_forwarded_request(
+    request: ForwardedRequest,
+    client: &reqwest::Client,
+    args: &Args,
+) -> Result<LocalResponse, Box<dyn std::error::Error>> {
     info!("Processing request: {} {}", request.method, request.path);
+    check_allowed(args, &request.method, &request.path).map_err(AgentError)?;
+
+    if args.echo {
+        return echo_request(request);
+    }
+    let local_timeout = Duration::from_secs(args.local_timeout);
+    let max_response_size = args.max_response_size;
     
     // Create the full URL for the local server
-    let local_url = format!("{}{}", LOCAL_APP_URL, request.path);
+    validate_request_path(&request.path).map_err(AgentError)?;
+    let local_url = resolve_local_url(args, &request.path);
     info!("Forwarding to local server: {}", local_url);
 
-    // Create HTTP client
-    let client = reqwest::Client::new();
-
     // Create the request
-    let mut req_builder = match request.method.as_str() {
-        "GET" => client.get(&local_url),
-        "POST" => client.post(&local_url),
-        "PUT" => client.put(&local_url),
-        "DELETE" => client.delete(&local_url),
-        _ => return Err(AgentError(format!("Unsupported method: {}", request.method)).into()),
-    };
+    let method = reqwest::Method::from_str(&request.method)
+        .ok()
+        .filter(|method| SUPPORTED_METHODS.contains(method))
+        .ok_or_else(|| AgentError(format!("Unsupported method: {}", request.method)))?;
+    let mut req_builder = client.request(method.clone(), &local_url);
+
+    // Check whether the forwarded body is JSON before the headers are consumed
+    let is_json = request.headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("content-type"))
+        .is_some_and(|(_, value)| is_json_content_type(value));
 
-    // Add headers
+    // Add headers, with the Host the local app expects rather than the gateway's public one
+    if let Some(host) = local_host_header(args, &request.headers) {
+        req_builder = req_builder.header(reqwest::header::HOST, host);
+    }
     for (key, value) in request.headers {
-        req_builder = req_builder.header(key, value);
+        if !key.eq_ignore_ascii_case("host") {
+            req_builder = req_builder.header(key, value);
+        }
     }
 
-    // Add body for non-GET requests
-    if request.method != "GET" {
-        let body: serde_json::Value = serde_json::from_str(&request.body)
-            .map_err(|e| AgentError(format!("Failed to parse request body: {}", e)))?;
-        req_builder = req_builder.json(&body);
+    // Add body for methods that carry one: binary bodies are decoded and sent byte for byte (keeping
+    // multipart boundaries intact), JSON is re-encoded, anything else (forms, text) is sent as-is
+    if method != reqwest::Method::GET && method != reqwest::Method::HEAD && !request.body.is_empty() {
+        let body = if request.binary {
+            BASE64.decode(request.body.as_bytes())
+                .map_err(|e| AgentError(format!("Failed to decode binary request body: {}", e)))?
+        } else if is_json {
+            let body: serde_json::Value = serde_json::from_str(&request.body)
+                .map_err(|e| AgentError(format!("Failed to parse request body: {}", e)))?;
+            serde_json::to_vec(&body)?
+        } else {
+            request.body.into_bytes()
+        };
+        // A streamed body has no known length, so it goes out chunked like the client sent it
+        req_builder = if request.chunked {
+            req_builder.body(reqwest::Body::wrap_stream(futures_util::stream::iter([Ok::<_, std::io::Error>(body)])))
+        } else {
+            req_builder.body(body)
+        };
+    }
+    // reqwest can't send trailers, so the local app never sees them
+    if !request.trailers.is_empty() {
+        let names: Vec<&str> = request.trailers.iter().map(|(name, _)| name.as_str()).collect();
+        warn!("Dropping request trailers the local server can't be sent: {}", names.join(", "));
     }
 
-    // Send request to local server
-    let local_response = req_builder.send().await
-        .map_err(|e| AgentError(format!("Failed to forward request to local server: {}", e)))?;
+    // Send request to local server. Idempotent requests are retried while the local app refuses
+    // connections, so a brief restart isn't surfaced as a tunnel error
+    let retries = if method == reqwest::Method::GET || method == reqwest::Method::HEAD { args.local_retries } else { 0 };
+    // The timeout covers the whole exchange, retries and their backoff included, and reading the
+    // body (except event streams), so the gateway always gets an answer before it gives up itself
+    let deadline = Instant::now() + local_timeout;
+    let mut attempt = 0;
+    let mut local_response = loop {
+        // GET and HEAD have no body to consume, so their request can always be cloned
+        let retry = req_builder.try_clone().filter(|_| attempt < retries);
+        let error = match timeout_at(deadline, req_builder.send()).await {
+            Ok(Ok(response)) => break response,
+            Ok(Err(e)) if e.is_connect() => AgentError(format!("Failed to forward request to local server: {}", e)),
+            Ok(Err(e)) => return Err(AgentError(format!("Failed to forward request to local server: {}", e)).into()),
+            Err(_) => return Err(local_timeout_error(local_timeout).into()),
+        };
+        let delay = Duration::from_millis(LOCAL_RETRY_DELAY_MS << attempt.min(6));
+        match retry {
+            Some(next) if Instant::now() + delay < deadline => {
+                attempt += 1;
+                warn!("Local server unavailable ({}), retry {} of {} in {}ms", error, attempt, retries, delay.as_millis());
+                sleep(delay).await;
+                req_builder = next;
+            }
+            _ => return Err(error.into()),
+        }
+    };
     
-    // Get response status
+    // Get response status, with the reason phrase as the local app sent it (hyper only records one
+    // that differs from the standard phrase for the code)
     let status = local_response.status();
+    let reason = match local_response.extensions().get::<hyper::ext::ReasonPhrase>() {
+        Some(reason) => String::from_utf8_lossy(reason.as_bytes()).into_owned(),
+        None => status.canonical_reason().unwrap_or_default().to_string(),
+    };
     
     // Get response headers
     let headers: Vec<(String, String)> = local_response.headers()
@@ -107,105 +768,641 @@ async fn handle_forwarded_request(request: ForwardedRequest) -> Result<String, B
         })
         .collect();
 
-    // Get response body
-    let body = local_response.text().await
-        .map_err(|e| AgentError(format!("Failed to read local server response: {}", e)))?;
+    // Refuse oversized bodies up front when the local app declares their length
+    if let Some(length) = local_response.content_length().filter(|&length| length > max_response_size) {
+        return Err(response_too_large(length, max_response_size).into());
+    }
+
+    let mut data = serde_json::json!({
+        "status_code": status.as_u16(),
+        "reason": reason,
+        "headers": headers,
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "agent_version": env!("CARGO_PKG_VERSION"),
+    });
+
+    // Large bodies are streamed in chunks instead of being buffered into one frame, as are
+    // Server-Sent Events, which would otherwise never finish buffering
+    let event_stream = local_response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(is_event_stream_content_type);
+    let streamed = event_stream
+        || local_response
+            .content_length()
+            .is_some_and(|length| length > args.stream_threshold);
+    let body = if streamed {
+        data["streamed"] = serde_json::Value::Bool(true);
+        Some(local_response)
+    } else {
+        // Read incrementally so a body without a declared length is cut off at the limit
+        let mut body = Vec::new();
+        while let Some(piece) = timeout_at(deadline, local_response.chunk()).await
+            .map_err(|_| local_timeout_error(local_timeout))?
+            .map_err(|e| AgentError(format!("Failed to read local server response: {}", e)))?
+        {
+            let length = (body.len() + piece.len()) as u64;
+            if length > max_response_size {
+                return Err(response_too_large(length, max_response_size).into());
+            }
+            body.extend_from_slice(&piece);
+        }
+        data["body"] = serde_json::Value::String(String::from_utf8_lossy(&body).into_owned());
+        None
+    };
 
     // Create response
     let response = AgentResponse {
         status: if status.is_success() { "success".to_string() } else { "error".to_string() },
         message: format!("Local server responded with status {}", status),
+        data: Some(data),
+    };
+
+    // Serialize response
+    let serialized = serde_json::to_string(&response)
+        .map_err(|e| AgentError(format!("Failed to serialize response: {}", e)))?;
+
+    Ok(match body {
+        Some(body) => LocalResponse::Streamed { head: serialized, body, deadline, event_stream },
+        None => LocalResponse::Buffered(serialized),
+    })
+}
+
+// Describe the request back to the gateway in the usual envelope, for smoke-testing a
+// tunnel before the local app exists
+fn echo_request(request: ForwardedRequest) -> Result<LocalResponse, Box<dyn std::error::Error>> {
+    let body = serde_json::to_string(&serde_json::json!({
+        "method": request.method,
+        "path": request.path,
+        "headers": request.headers,
+        "body": request.body,
+        "binary": request.binary,
+        "chunked": request.chunked,
+        "trailers": request.trailers,
+    }))?;
+
+    let response = AgentResponse {
+        status: "success".to_string(),
+        message: "Echoed request".to_string(),
         data: Some(serde_json::json!({
-            "status_code": status.as_u16(),
-            "headers": headers,
+            "status_code": 200,
+            "reason": "OK",
+            "headers": [["content-type", "application/json"]],
             "body": body,
             "timestamp": chrono::Utc::now().to_rfc3339(),
             "agent_version": env!("CARGO_PKG_VERSION"),
         })),
     };
+    let serialized = serde_json::to_string(&response)
+        .map_err(|e| AgentError(format!("Failed to serialize response: {}", e)))?;
+    Ok(LocalResponse::Buffered(serialized))
+}
 
-    // Serialize response
-    serde_json::to_string(&response)
-        .map_err(|e| AgentError(format!("Failed to serialize response: {}", e)).into())
+fn local_timeout_error(local_timeout: Duration) -> AgentError {
+    AgentError(format!("Local server did not respond within {}s", local_timeout.as_secs()))
+}
+
+fn response_too_large(length: u64, max_response_size: u64) -> AgentError {
+    AgentError(format!(
+        "Local server response of at least {} bytes exceeds the {} byte limit",
+        length, max_response_size
+    ))
+}
+
+// Relay a streamed local body as base64 "response_chunk" messages followed by "response_end",
+// failing once more than max_response_size bytes arrive (the declared length can't be trusted) or
+// at the deadline. Event streams are exempt from both and each piece is sent as soon as it arrives,
+// so events reach the client without waiting for a full chunk
+async fn send_streamed_body(
+    replies: &mpsc::Sender<GatewayMessage>,
+    body: reqwest::Response,
+    max_response_size: u64,
+    deadline: Instant,
+    event_stream: bool,
+    request_id: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut stream = body.bytes_stream();
+    let mut buffer: Vec<u8> = Vec::with_capacity(STREAM_CHUNK_SIZE);
+    let mut sequence = 0;
+    let mut total: u64 = 0;
+
+    loop {
+        let piece = if event_stream {
+            stream.next().await
+        } else {
+            timeout_at(deadline, stream.next()).await.map_err(|_| AgentError("Local server response timed out".to_string()))?
+        };
+        let piece = piece.transpose()
+            .map_err(|e| AgentError(format!("Failed to read local server response: {}", e)))?;
+        if let Some(piece) = &piece {
+            total += piece.len() as u64;
+            if total > max_response_size && !event_stream {
+                return Err(response_too_large(total, max_response_size).into());
+            }
+            buffer.extend_from_slice(piece);
+        }
+
+        // Flush full chunks, and whatever is left once the body is exhausted (or at once for events)
+        let flush_all = piece.is_none() || event_stream;
+        while buffer.len() >= STREAM_CHUNK_SIZE || (flush_all && !buffer.is_empty()) {
+            let take = buffer.len().min(STREAM_CHUNK_SIZE);
+            let chunk: Vec<u8> = buffer.drain(..take).collect();
+            let chunk_msg = GatewayMessage {
+                sequence: Some(sequence),
+                ..GatewayMessage::reply("response_chunk", BASE64.encode(chunk), request_id)
+            };
+            replies.send(chunk_msg).await?;
+            sequence += 1;
+        }
+
+        if piece.is_none() {
+            break;
+        }
+    }
+
+    let end_msg = GatewayMessage {
+        sequence: Some(sequence),
+        ..GatewayMessage::reply("response_end", String::new(), request_id)
+    };
+    replies.send(end_msg).await?;
+    info!("Streamed response body to gateway in {} chunks", sequence);
+    Ok(())
+}
+
+// Handle one forwarded request once a --concurrency permit is free, queueing each reply for the
+// connection's main loop to write. Aborting the task (on "cancel" or disconnect) drops the local call
+async fn serve_forwarded_request(
+    request: ForwardedRequest,
+    client: reqwest::Client,
+    args: Arc<Args>,
+    permits: Arc<Semaphore>,
+    replies: mpsc::Sender<GatewayMessage>,
+) {
+    let Ok(_permit) = permits.acquire_owned().await else {
+        return;
+    };
+    let request_id = request.request_id.clone();
+    let request_id = request_id.as_deref();
+    // Errors are rendered at once, as the boxed error can't be held across an await in a task
+    let result = handle_forwarded_request(request, &client, &args).await.map_err(|e| e.to_string());
+    let reply = match result {
+        Ok(LocalResponse::Buffered(response)) => GatewayMessage::reply("response", response, request_id),
+        Ok(LocalResponse::Streamed { head, body, deadline, event_stream }) => {
+            if replies.send(GatewayMessage::reply("response", head, request_id)).await.is_err() {
+                return;
+            }
+            let streamed = send_streamed_body(&replies, body, args.max_response_size, deadline, event_stream, request_id).await;
+            match streamed {
+                Ok(()) => return,
+                Err(e) => {
+                    error!("Failed to stream response body: {}", e);
+                    // Tell the gateway to abandon the partial body
+                    GatewayMessage::reply("error", e.to_string(), request_id)
+                }
+            }
+        }
+        Err(e) => {
+            error!("Failed to handle request: {}", e);
+            GatewayMessage::reply("error", e, request_id)
+        }
+    };
+    let is_response = reply.message_type == "response";
+    // Fails only once the connection the reply was meant for has ended
+    if replies.send(reply).await.is_ok() && is_response {
+        info!("Response sent to gateway");
+    }
+}
+
+// Map a local HTTP URL onto the equivalent WebSocket URL
+fn local_websocket_url(http_url: &str) -> String {
+    if let Some(rest) = http_url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = http_url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        http_url.to_string()
+    }
+}
+
+// Tell the gateway a tunneled stream is finished
+fn send_tunnel_close(outbound: &mpsc::UnboundedSender<GatewayMessage>, stream_id: &str, code: Option<u16>, reason: &str) {
+    let close = TunnelClose {
+        stream_id: stream_id.to_string(),
+        code,
+        reason: reason.to_string(),
+    };
+    if let Ok(payload) = serde_json::to_string(&close) {
+        let _ = outbound.send(GatewayMessage::new("ws_close", payload));
+    }
+}
+
+// Proxy one tunneled WebSocket between the gateway and the local app
+async fn run_tunnel_stream(
+    open: TunnelOpen,
+    local_url: String,
+    mut frames_rx: mpsc::UnboundedReceiver<Message>,
+    outbound: mpsc::UnboundedSender<GatewayMessage>,
+    tunnels: TunnelMap,
+    local_insecure: bool,
+    host: Option<String>,
+) {
+    let stream_id = open.stream_id;
+
+    let local_socket = async {
+        // The Host header defaults to the local URL's, as for forwarded requests
+        let mut request = local_url.as_str().into_client_request()?;
+        for (key, value) in open.headers.iter().filter(|(key, _)| !key.eq_ignore_ascii_case("host")) {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_str(key), HeaderValue::from_str(value)) {
+                request.headers_mut().insert(name, value);
+            }
+        }
+        if let Some(host) = host.as_deref().and_then(|host| HeaderValue::from_str(host).ok()) {
+            request.headers_mut().insert("host", host);
+        }
+        // --local-insecure skips certificate checks for wss:// local apps as it does for https://
+        let connector = if local_insecure {
+            let tls = native_tls::TlsConnector::builder()
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true)
+                .build()
+                .map_err(|e| WsError::Tls(TlsError::Native(e)))?;
+            Some(Connector::NativeTls(tls))
+        } else {
+            None
+        };
+        connect_async_tls_with_config(request, None, false, connector).await.map(|(socket, _)| socket)
+    };
+    let (mut local_sink, mut local_stream) = match local_socket.await {
+        Ok(socket) => socket.split(),
+        Err(e) => {
+            error!("Failed to open local WebSocket {}: {}", local_url, e);
+            tunnels.lock().unwrap().remove(&stream_id);
+            send_tunnel_close(&outbound, &stream_id, Some(1011), &format!("Failed to open local WebSocket: {}", e));
+            return;
+        }
+    };
+
+    loop {
+        tokio::select! {
+            // Frames from the gateway; the sender is dropped when the gateway connection ends
+            message = frames_rx.recv() => {
+                let Some(message) = message else {
+                    let _ = local_sink.send(Message::Close(None)).await;
+                    break;
+                };
+                let closing = matches!(message, Message::Close(_));
+                if let Err(e) = local_sink.send(message).await {
+                    warn!("Failed to write to local WebSocket {}: {}", stream_id, e);
+                    send_tunnel_close(&outbound, &stream_id, None, "Local WebSocket write failed");
+                    break;
+                }
+                if closing {
+                    break;
+                }
+            }
+            // Frames from the local app
+            message = local_stream.next() => {
+                let frame = match message {
+                    Some(Ok(Message::Text(text))) => TunnelFrame { stream_id: stream_id.clone(), data: text, binary: false },
+                    Some(Ok(Message::Binary(data))) => TunnelFrame { stream_id: stream_id.clone(), data: BASE64.encode(data), binary: true },
+                    Some(Ok(Message::Close(frame))) => {
+                        let (code, reason) = frame
+                            .map(|frame| (Some(u16::from(frame.code)), frame.reason.into_owned()))
+                            .unwrap_or((None, String::new()));
+                        send_tunnel_close(&outbound, &stream_id, code, &reason);
+                        break;
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) | None => {
+                        send_tunnel_close(&outbound, &stream_id, None, "Local WebSocket disconnected");
+                        break;
+                    }
+                };
+                let Ok(payload) = serde_json::to_string(&frame) else { continue };
+                if outbound.send(GatewayMessage::new("ws_frame", payload)).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Flushes the close reply queued when the local app initiated the close
+    let _ = local_sink.close().await;
+    tunnels.lock().unwrap().remove(&stream_id);
+    info!("Tunneled WebSocket {} closed", stream_id);
+}
+
+// Probe the local app's health path; the expected status (or any 2xx) counts as healthy
+async fn probe_local_health(client: &reqwest::Client, args: &Args, health_path: &str) -> bool {
+    let expect_status = args.local_health_expect_status;
+    let url = resolve_local_url(args, health_path);
+    let probe = client.get(&url).timeout(Duration::from_secs(LOCAL_HEALTH_TIMEOUT_SECS));
+    match probe.send().await {
+        Ok(response) if expect_status.map_or(response.status().is_success(), |code| response.status().as_u16() == code) => {
+            info!("Local app at {} is healthy", url);
+            true
+        }
+        Ok(response) => {
+            warn!("Local app at {} is unhealthy: {}", url, response.status());
+            false
+        }
+        Err(e) => {
+            warn!("Local app at {} is unreachable: {}", url, e);
+            false
+        }
+    }
+}
+
+// Re-probe the local app while connected and tell the gateway whenever its health changes
+async fn monitor_local_health(
+    client: reqwest::Client,
+    args: Arc<Args>,
+    health_path: String,
+    mut healthy: bool,
+    outbound: mpsc::UnboundedSender<GatewayMessage>,
+) {
+    let interval = Duration::from_secs(args.local_health_interval);
+    loop {
+        tokio::time::sleep(interval).await;
+        // The connection this monitor reports on has ended
+        if outbound.is_closed() {
+            return;
+        }
+
+        let now_healthy = probe_local_health(&client, &args, &health_path).await;
+        if now_healthy == healthy {
+            continue;
+        }
+        healthy = now_healthy;
+
+        let payload = serde_json::json!({ "healthy": healthy }).to_string();
+        if outbound.send(GatewayMessage::new("health", payload)).is_err() {
+            return;
+        }
+    }
+}
+
+// Close code and reason for the logs, e.g. "code 1001: Gateway shutting down"; 1005 is the
+// standard code for a close frame without one
+fn describe_close(frame: Option<&CloseFrame>) -> String {
+    match frame {
+        Some(frame) if frame.reason.is_empty() => format!("code {}", u16::from(frame.code)),
+        Some(frame) => format!("code {}: {}", u16::from(frame.code), frame.reason),
+        None => "code 1005, no reason given".to_string(),
+    }
+}
+
+// After the handshake, wait for the gateway to accept it and return the connection ID from its
+// welcome. The gateway sends no acknowledgement, but it handles messages in order: a heartbeat
+// sent after the handshake is only answered if the handshake passed, and a rejection arrives
+// as an "error" message first
+async fn confirm_handshake<W, R>(write: &mut GatewayWriter<W>, read: &mut R) -> Result<String, AgentError>
+where
+    W: Sink<Message, Error = WsError> + Unpin,
+    R: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    write.send(GatewayMessage::new("heartbeat", String::new())).await
+        .map_err(|e| AgentError(format!("Failed to send heartbeat: {}", e)))?;
+
+    let deadline = Instant::now() + Duration::from_secs(CHECK_TIMEOUT_SECS);
+    let mut connection_id = None;
+    loop {
+        let msg = timeout_at(deadline, read.next()).await
+            .map_err(|_| AgentError(format!("Gateway did not confirm the handshake within {}s", CHECK_TIMEOUT_SECS)))?;
+        let text = match msg {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(frame))) => {
+                return Err(AgentError(format!("Gateway closed the connection during the handshake ({})", describe_close(frame.as_ref()))));
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(AgentError(format!("Connection failed during the handshake: {}", e))),
+            None => return Err(AgentError("Gateway closed the connection during the handshake".to_string())),
+        };
+        let Ok(msg) = serde_json::from_str::<GatewayMessage>(&text) else {
+            continue;
+        };
+        match msg.message_type.as_str() {
+            "welcome" => match serde_json::from_str::<Welcome>(&msg.payload) {
+                Ok(welcome) => connection_id = Some(welcome.connection_id),
+                Err(e) => return Err(AgentError(format!("Invalid welcome payload: {}", e))),
+            },
+            "error" => return Err(AgentError(format!("Gateway rejected the handshake: {}", msg.payload))),
+            "heartbeat_ack" => {
+                return connection_id.ok_or_else(|| AgentError("Gateway sent no welcome message".to_string()));
+            }
+            _ => {}
+        }
+    }
 }
 
 async fn connect_to_gateway(
-    tunnel_id: String,
-    shutdown_rx: broadcast::Receiver<()>
+    args: &Args,
+    url: &Url,
+    client: &reqwest::Client,
+    shutdown_rx: broadcast::Receiver<()>,
+    last_connection_id: &mut Option<String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let gateway_url = env::var("GATEWAY_URL")
-        .unwrap_or_else(|_| "ws://127.0.0.1:3000".to_string());
-    let ws_url = format!("{}/ws", gateway_url);
+    let ws_url = gateway_ws_url(url, &args.ws_path);
+    info!("Connecting to gateway at: {}", ws_url);
     
-    let url = Url::parse(&ws_url)
-        .map_err(|e| AgentError(format!("Invalid gateway URL: {}", e)))?;
-    
-    info!("Connecting to gateway at: {}", url);
-    
-    let (ws_stream, _) = connect_async(url).await
+    // Oversized messages from the gateway fail the read instead of being buffered
+    let config = WebSocketConfig {
+        max_message_size: Some(args.max_message_size),
+        max_frame_size: Some(args.max_message_size),
+        ..Default::default()
+    };
+    let (ws_stream, _) = connect_async_with_config(ws_url.as_str(), Some(config), false).await
         .map_err(|e| AgentError(format!("Failed to connect: {}", e)))?;
     
-    info!("WebSocket connection established");
-    let (mut write, mut read) = ws_stream.split();
+    info!("WebSocket connection established, active gateway is {}", url);
+    let (sink, mut read) = ws_stream.split();
+    let mut write = GatewayWriter::new(sink);
 
-    // Send handshake
+    // Send handshake, reporting the local app as degraded if its health probe fails
+    // (echo mode never touches the local app, so there is nothing to probe)
+    let local_healthy = match &args.local_health_path {
+        Some(path) if !args.echo => {
+            probe_local_health(client, args, path).await
+        }
+        _ => true,
+    };
     let handshake = AgentHandshake {
-        tunnel_id: tunnel_id.clone(),
+        tunnel_id: args.tunnel().id.clone(),
         agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        auth_token: args.auth_token.clone(),
+        local_healthy,
+        previous_connection_id: last_connection_id.clone(),
+        labels: args.labels.iter().cloned().collect(),
+        local_url: args.tunnel().local_url.clone(),
+        routes: args.routes.iter().map(|route| (route.prefix.clone(), route.target.clone())).collect(),
+        concurrency: args.concurrency,
+        allowed_methods: args.allowed_methods.clone(),
+        allowed_paths: args.allowed_paths.clone(),
+        compression: args.ws_compress.then(|| COMPRESSION_GZIP.to_string()),
     };
 
     let handshake_msg = serde_json::to_string(&handshake)
         .map_err(|e| AgentError(format!("Failed to serialize handshake: {}", e)))?;
 
-    write.send(Message::Text(handshake_msg)).await
+    write.send_frame(Message::Text(handshake_msg)).await
         .map_err(|e| AgentError(format!("Failed to send handshake: {}", e)))?;
 
     info!("Handshake sent, awaiting response");
+    // A gateway that accepted the socket but is hung never sends its welcome
+    let welcome_deadline = Instant::now() + Duration::from_secs(args.welcome_timeout);
+    let mut welcomed = false;
+
+    // --check stops once the gateway has accepted the handshake
+    if args.check {
+        let connection_id = confirm_handshake(&mut write, &mut read).await?;
+        info!("Handshake accepted by {}, connection ID: {}", url, connection_id);
+        *last_connection_id = Some(connection_id);
+        let close = CloseFrame { code: CloseCode::Normal, reason: "Check complete".into() };
+        if let Err(e) = write.send_frame(Message::Close(Some(close))).await {
+            warn!("Failed to send close message: {}", e);
+        }
+        return Ok(());
+    }
 
     let mut ping_interval = tokio::time::interval(Duration::from_secs(PING_INTERVAL_SECS));
-    let mut received_connection_id = false;
     let mut shutdown_rx = shutdown_rx;
 
+    // Messages produced by tunneled WebSocket tasks, written to the gateway by this loop
+    let (outbound_tx, mut outbound_rx) = mpsc::unbounded_channel::<GatewayMessage>();
+    let tunnels: TunnelMap = Arc::new(Mutex::new(HashMap::new()));
+
+    let task_args = Arc::new(args.clone());
+    if let Some(path) = args.local_health_path.as_ref().filter(|_| !args.echo && args.local_health_interval > 0) {
+        tokio::spawn(monitor_local_health(
+            client.clone(),
+            Arc::clone(&task_args),
+            path.clone(),
+            local_healthy,
+            outbound_tx.clone(),
+        ).in_current_span());
+    }
+
+    // Forwarded requests run as tasks, at most --concurrency at once, replying through this channel.
+    // Dropping the set when the connection ends aborts whatever is still running
+    let (replies_tx, mut replies_rx) = mpsc::channel::<GatewayMessage>(REPLY_QUEUE_CAPACITY);
+    let permits = Arc::new(Semaphore::new(args.concurrency as usize));
+    let mut request_tasks = JoinSet::new();
+    // Running requests by ID, so a "cancel" can abort the right one
+    let mut in_flight: HashMap<String, AbortHandle> = HashMap::new();
+
     loop {
         tokio::select! {
             msg = read.next() => {
                 match msg {
                     Some(Ok(Message::Text(text))) => {
-                        if !received_connection_id {
-                            info!("Received connection ID: {}", text);
-                            received_connection_id = true;
-                            continue;
-                        }
-
-                        if let Ok(msg) = serde_json::from_str::<GatewayMessage>(&text) {
+                        if let Some(msg) = parse_gateway_message(&text, args.max_message_size) {
                             match msg.message_type.as_str() {
+                                "welcome" => {
+                                    match serde_json::from_str::<Welcome>(&msg.payload) {
+                                        Ok(welcome) => {
+                                            info!(
+                                                "Received connection ID: {} (gateway version {})",
+                                                welcome.connection_id, welcome.server_version
+                                            );
+                                            *last_connection_id = Some(welcome.connection_id);
+                                            welcomed = true;
+                                            if args.ws_compress {
+                                                write.compress = welcome.compression.as_deref() == Some(COMPRESSION_GZIP);
+                                                if !write.compress {
+                                                    warn!("Gateway doesn't support --ws-compress, sending responses uncompressed");
+                                                }
+                                            }
+                                        }
+                                        Err(e) => warn!("Invalid welcome payload: {}", e),
+                                    }
+                                }
+                                "heartbeat_ack" => {}
+                                "cancel" => {
+                                    // Nothing to abort if the request already completed
+                                    if let Some(task) = in_flight.remove(&msg.payload).filter(|task| !task.is_finished()) {
+                                        task.abort();
+                                        info!("Gateway cancelled request {}, local call aborted", msg.payload);
+                                    }
+                                }
                                 "request" => {
                                     info!("Received request from gateway");
                                     if let Ok(request) = serde_json::from_str::<ForwardedRequest>(&msg.payload) {
-                                        match handle_forwarded_request(request).await {
-                                            Ok(response) => {
-                                                let response_msg = GatewayMessage {
-                                                    message_type: "response".to_string(),
-                                                    payload: response,
-                                                };
-                                                if let Err(e) = write.send(Message::Text(serde_json::to_string(&response_msg)?)).await {
-                                                    error!("Failed to send response: {}", e);
-                                                    return Err(e.into());
-                                                }
-                                                info!("Response sent to gateway");
+                                        // Forget requests that have completed since the last one arrived
+                                        while request_tasks.try_join_next().is_some() {}
+                                        in_flight.retain(|_, task| !task.is_finished());
+                                        let request_id = request.request_id.clone();
+                                        let task = request_tasks.spawn(serve_forwarded_request(
+                                            request,
+                                            client.clone(),
+                                            Arc::clone(&task_args),
+                                            Arc::clone(&permits),
+                                            replies_tx.clone(),
+                                        ).in_current_span());
+                                        if let Some(request_id) = request_id {
+                                            in_flight.insert(request_id, task);
+                                        }
+                                    }
+                                }
+                                "ws_open" => {
+                                    match serde_json::from_str::<TunnelOpen>(&msg.payload) {
+                                        Ok(open) => {
+                                            if let Err(e) = validate_request_path(&open.path).and_then(|()| check_allowed(args, "GET", &open.path)) {
+                                                warn!("{}", e);
+                                                send_tunnel_close(&outbound_tx, &open.stream_id, Some(1008), &e);
+                                                continue;
                                             }
-                                            Err(e) => {
-                                                error!("Failed to handle request: {}", e);
-                                                let error_msg = GatewayMessage {
-                                                    message_type: "error".to_string(),
-                                                    payload: e.to_string(),
-                                                };
-                                                if let Err(e) = write.send(Message::Text(serde_json::to_string(&error_msg)?)).await {
-                                                    error!("Failed to send error response: {}", e);
-                                                    return Err(e.into());
+                                            let local_url = local_websocket_url(&resolve_local_url(args, &open.path));
+                                            let host = local_host_header(args, &open.headers);
+                                            info!("Opening tunneled WebSocket {} to {}", open.stream_id, local_url);
+                                            let (frames_tx, frames_rx) = mpsc::unbounded_channel();
+                                            tunnels.lock().unwrap().insert(open.stream_id.clone(), frames_tx);
+                                            tokio::spawn(run_tunnel_stream(
+                                                open,
+                                                local_url,
+                                                frames_rx,
+                                                outbound_tx.clone(),
+                                                Arc::clone(&tunnels),
+                                                args.local_insecure,
+                                                host,
+                                            ).in_current_span());
+                                        }
+                                        Err(e) => warn!("Invalid ws_open payload: {}", e),
+                                    }
+                                }
+                                "ws_frame" => {
+                                    match serde_json::from_str::<TunnelFrame>(&msg.payload) {
+                                        Ok(frame) => {
+                                            let message = if frame.binary {
+                                                match BASE64.decode(frame.data.as_bytes()) {
+                                                    Ok(data) => Message::Binary(data),
+                                                    Err(e) => {
+                                                        warn!("Invalid binary ws_frame for {}: {}", frame.stream_id, e);
+                                                        continue;
+                                                    }
                                                 }
+                                            } else {
+                                                Message::Text(frame.data)
+                                            };
+                                            if let Some(frames) = tunnels.lock().unwrap().get(&frame.stream_id) {
+                                                let _ = frames.send(message);
+                                            }
+                                        }
+                                        Err(e) => warn!("Invalid ws_frame payload: {}", e),
+                                    }
+                                }
+                                "ws_close" => {
+                                    match serde_json::from_str::<TunnelClose>(&msg.payload) {
+                                        Ok(close) => {
+                                            info!("Gateway closed tunneled WebSocket {}", close.stream_id);
+                                            if let Some(frames) = tunnels.lock().unwrap().remove(&close.stream_id) {
+                                                let _ = frames.send(Message::Close(Some(CloseFrame {
+                                                    code: close.code.map(CloseCode::from).unwrap_or(CloseCode::Normal),
+                                                    reason: close.reason.into(),
+                                                })));
                                             }
                                         }
+                                        Err(e) => warn!("Invalid ws_close payload: {}", e),
                                     }
                                 }
                                 "error" => {
@@ -219,12 +1416,18 @@ async fn connect_to_gateway(
                             }
                         }
                     }
-                    Some(Ok(Message::Close(_))) => {
-                        info!("Gateway closed connection gracefully");
+                    Some(Ok(Message::Close(frame))) => {
+                        // Policy closes are rejections and Again means the gateway is full; back off for both
+                        if frame.as_ref().is_some_and(|f| matches!(f.code, CloseCode::Policy | CloseCode::Again)) {
+                            let error_msg = format!("Gateway rejected connection ({})", describe_close(frame.as_ref()));
+                            error!("{}", error_msg);
+                            return Err(AgentError(error_msg).into());
+                        }
+                        info!("Gateway closed connection ({})", describe_close(frame.as_ref()));
                         return Ok(());
                     }
                     Some(Ok(Message::Ping(data))) => {
-                        if let Err(e) = write.send(Message::Pong(data)).await {
+                        if let Err(e) = write.send_frame(Message::Pong(data)).await {
                             error!("Failed to send pong: {}", e);
                             return Err(e.into());
                         }
@@ -244,15 +1447,38 @@ async fn connect_to_gateway(
                     _ => {}
                 }
             }
+            Some(message) = replies_rx.recv() => {
+                if let Err(e) = write.send(message).await {
+                    error!("Failed to send response: {}", e);
+                    return Err(e.into());
+                }
+            }
+            Some(message) = outbound_rx.recv() => {
+                if let Err(e) = write.send(message).await {
+                    error!("Failed to send tunneled WebSocket message: {}", e);
+                    return Err(e.into());
+                }
+            }
             _ = ping_interval.tick() => {
-                if let Err(e) = write.send(Message::Ping(vec![])).await {
+                if let Err(e) = write.send_frame(Message::Ping(vec![])).await {
                     error!("Failed to send ping: {}", e);
                     return Err(AgentError(format!("Failed to send ping: {}", e)).into());
                 }
+                // Also send a text heartbeat, which survives proxies that strip ping frames
+                if let Err(e) = write.send(GatewayMessage::new("heartbeat", String::new())).await {
+                    error!("Failed to send heartbeat: {}", e);
+                    return Err(e.into());
+                }
+            }
+            _ = tokio::time::sleep_until(welcome_deadline), if !welcomed && args.welcome_timeout > 0 => {
+                let error_msg = format!("Gateway sent no welcome within {}s", args.welcome_timeout);
+                error!("{}", error_msg);
+                return Err(AgentError(error_msg).into());
             }
             _ = shutdown_rx.recv() => {
                 info!("Shutdown signal received, closing connection...");
-                if let Err(e) = write.send(Message::Close(None)).await {
+                let close = CloseFrame { code: CloseCode::Away, reason: "Agent shutting down".into() };
+                if let Err(e) = write.send_frame(Message::Close(Some(close))).await {
                     warn!("Failed to send close message: {}", e);
                 }
                 return Ok(());
@@ -261,35 +1487,87 @@ async fn connect_to_gateway(
     }
 }
 
-async fn connect_with_retry(tunnel_id: String, shutdown_rx: broadcast::Receiver<()>) -> i32 {
+// --check: try the handshake once against every gateway, reporting each result
+async fn check_gateways(args: &Args, client: &reqwest::Client) -> i32 {
+    let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+    let mut exit_code = 0;
+    for tunnel in &args.tunnels {
+        let args = args.for_tunnel(tunnel);
+        for url in &args.gateway_urls {
+            let mut connection_id = None;
+            match connect_to_gateway(&args, url, client, shutdown_rx.resubscribe(), &mut connection_id).await {
+                Ok(()) => println!("OK {} {} (connection {})", url, tunnel.id, connection_id.unwrap_or_default()),
+                Err(e) => {
+                    println!("FAILED {} {}: {}", url, tunnel.id, e);
+                    exit_code = GATEWAY_UNREACHABLE_EXIT_CODE;
+                }
+            }
+        }
+    }
+    exit_code
+}
+
+async fn connect_with_retry(args: &Args, client: &reqwest::Client, shutdown_rx: broadcast::Receiver<()>) -> i32 {
     let mut retry_count = 0;
-    let mut delay_ms = INITIAL_RETRY_DELAY_MS;
+    let mut delay_ms = args.initial_retry_delay_ms;
     let mut shutdown_rx = shutdown_rx;
+    // Reported in the next handshake so the gateway can link reconnections
+    let mut last_connection_id = None;
+    // Gateway currently in use, and how many gateways have failed in a row since one last worked
+    let mut gateway_index = 0;
+    let mut failed_gateways = 0;
 
     loop {
-        info!("Connection attempt {} of {}", retry_count + 1, MAX_RETRIES);
+        if args.max_retries == 0 {
+            info!("Connection attempt {}", retry_count + 1);
+        } else {
+            info!("Connection attempt {} of {}", retry_count + 1, args.max_retries);
+        }
         
-        match connect_to_gateway(tunnel_id.clone(), shutdown_rx.resubscribe()).await {
+        let gateway_url = &args.gateway_urls[gateway_index];
+        // Only the message is kept, as the error type isn't Send and the retry delay awaits
+        let result = connect_to_gateway(args, gateway_url, client, shutdown_rx.resubscribe(), &mut last_connection_id)
+            .await
+            .map_err(|e| e.to_string());
+        match result {
             Ok(_) => {
-                info!("Connection closed gracefully, attempting to reconnect...");
+                // The connection also ends cleanly when it was closed for a shutdown
+                if shutdown_rx.try_recv().is_ok() {
+                    return SHUTDOWN_EXIT_CODE;
+                }
+                info!("Connection to {} closed gracefully, attempting to reconnect...", gateway_url);
                 retry_count = 0;
-                delay_ms = INITIAL_RETRY_DELAY_MS;
+                failed_gateways = 0;
+                delay_ms = args.initial_retry_delay_ms;
             }
             Err(e) => {
-                error!("Connection error: {}", e);
+                error!("Connection error on {}: {}", gateway_url, e);
                 retry_count += 1;
                 
-                if retry_count >= MAX_RETRIES {
-                    error!("Max retries ({}) reached, exiting...", MAX_RETRIES);
+                if args.max_retries != 0 && retry_count >= args.max_retries {
+                    error!("Max retries ({}) reached, exiting...", args.max_retries);
                     return GATEWAY_UNREACHABLE_EXIT_CODE;
                 }
+
+                // Move on to the next gateway straight away, backing off only once all of them have failed
+                if args.gateway_urls.len() > 1 {
+                    gateway_index = (gateway_index + 1) % args.gateway_urls.len();
+                    failed_gateways += 1;
+                    warn!("Failing over to gateway {}", args.gateway_urls[gateway_index]);
+                    if failed_gateways < args.gateway_urls.len() {
+                        continue;
+                    }
+                    failed_gateways = 0;
+                }
                 
-                delay_ms = std::cmp::min(delay_ms * 2, MAX_RETRY_DELAY_MS);
-                info!("Retrying in {} ms...", delay_ms);
+                delay_ms = std::cmp::min(delay_ms.saturating_mul(2), args.max_retry_delay_ms);
+                // Jitter spreads out a fleet of agents reconnecting after the same gateway blip
+                let jittered_ms = (delay_ms as f64 * rand::thread_rng().gen_range(1.0 - RETRY_JITTER..=1.0 + RETRY_JITTER)) as u64;
+                info!("Retrying in {} ms...", jittered_ms);
 
                 // Add shutdown check during retry delay
                 tokio::select! {
-                    _ = sleep(Duration::from_millis(delay_ms)) => {}
+                    _ = sleep(Duration::from_millis(jittered_ms)) => {}
                     _ = shutdown_rx.recv() => {
                         info!("Shutdown signal received during retry delay");
                         return SHUTDOWN_EXIT_CODE;
@@ -302,16 +1580,47 @@ async fn connect_with_retry(tunnel_id: String, shutdown_rx: broadcast::Receiver<
 
 #[tokio::main]
 async fn main() {
-    // Initialize logging
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
-
     // Parse command line arguments
     let args = Args::parse();
-    let tunnel_id = args.tunnel_id;
 
-    info!("Starting agent with tunnel_id: {}", tunnel_id);
+    if let Some(Command::GenerateId { purpose }) = &args.command {
+        println!("{}", generate_tunnel_id(purpose));
+        return;
+    }
+
+    // Initialize logging
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env());
+    match args.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+
+    for tunnel in &args.tunnels {
+        info!("Starting tunnel {} for {}", tunnel.id, tunnel.local_url);
+    }
+    for route in &args.routes {
+        info!("Routing {} to {}", route.prefix, route.target);
+    }
+    if args.auth_token.is_none() {
+        warn!("No auth token configured (--auth-token or TUNNEL_TOKEN), handshake will be unauthenticated");
+    }
+
+    // One client for all local requests, so connections to the local app are kept alive and reused
+    if args.local_insecure {
+        warn!("--local-insecure is set, certificates of https:// local apps are not verified");
+    }
+    let client = match reqwest::Client::builder().danger_accept_invalid_certs(args.local_insecure).build() {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to build HTTP client: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if args.check {
+        std::process::exit(check_gateways(&args, &client).await);
+    }
 
     // Create shutdown channel
     let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
@@ -326,7 +1635,26 @@ async fn main() {
         }
     });
 
-    // Start connection loop
-    let exit_code = connect_with_retry(tunnel_id, shutdown_rx).await;
+    // Each tunnel has its own connection and retry loop, and the agent exits once all of them have
+    // stopped, with a failure if any gave up
+    let mut connections = JoinSet::new();
+    for tunnel in &args.tunnels {
+        let args = args.for_tunnel(tunnel);
+        let client = client.clone();
+        let shutdown_rx = shutdown_rx.resubscribe();
+        let span = info_span!("tunnel", tunnel_id = %tunnel.id);
+        connections.spawn(async move { connect_with_retry(&args, &client, shutdown_rx).await }.instrument(span));
+    }
+    let mut exit_code = SHUTDOWN_EXIT_CODE;
+    while let Some(result) = connections.join_next().await {
+        match result {
+            Ok(SHUTDOWN_EXIT_CODE) => {}
+            Ok(code) => exit_code = code,
+            Err(e) => {
+                error!("Tunnel connection task failed: {}", e);
+                exit_code = GATEWAY_UNREACHABLE_EXIT_CODE;
+            }
+        }
+    }
     std::process::exit(exit_code);
 } 
\ No newline at end of file
@@ -0,0 +1,137 @@
+// End-to-end tests: the agent binary runs against a mock gateway speaking the WebSocket protocol
+// to it on an ephemeral port, forwarding requests to a local app the test controls.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::{
+    io::{Read, Write},
+    net::SocketAddr,
+    process::Stdio,
+    time::Duration,
+};
+use tokio::{net::TcpListener, process::Child, time::Instant};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+const TUNNEL_ID: &str = "agent_7f1c2d3e-1111-4222-8333-444455556666_web";
+
+// Start the agent with the given extra flags against the gateway listening on `gateway`
+fn start_agent(gateway: SocketAddr, flags: &[&str]) -> Child {
+    tokio::process::Command::new(env!("CARGO_BIN_EXE_agent"))
+        .arg("--gateway-url")
+        .arg(format!("ws://{}", gateway))
+        .args(flags)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap()
+}
+
+// Accept the agent's connection, read its handshake and welcome it
+async fn accept_agent(listener: &TcpListener) -> WebSocketStream<tokio::net::TcpStream> {
+    let welcome = json!({ "connection_id": "test-connection", "server_version": "0.1.0" });
+    accept_agent_with(listener, welcome).await.0
+}
+
+// Accept the agent's connection with `welcome`, returning its handshake too
+async fn accept_agent_with(listener: &TcpListener, welcome: Value) -> (WebSocketStream<tokio::net::TcpStream>, Value) {
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+    let Some(Ok(Message::Text(handshake))) = socket.next().await else {
+        panic!("no handshake");
+    };
+    let handshake: Value = serde_json::from_str(&handshake).unwrap();
+    assert_eq!(handshake["tunnel_id"], TUNNEL_ID);
+    let message = json!({ "message_type": "welcome", "payload": welcome.to_string() });
+    socket.send(Message::Text(message.to_string())).await.unwrap();
+    (socket, handshake)
+}
+
+// Forward a request to the agent as the gateway would
+async fn send_request(socket: &mut WebSocketStream<tokio::net::TcpStream>, method: &str, path: &str) {
+    let request = json!({
+        "method": method,
+        "path": path,
+        "body": "",
+        "headers": [],
+        "request_id": "test-request",
+    });
+    let message = json!({ "message_type": "request", "payload": request.to_string() });
+    socket.send(Message::Text(message.to_string())).await.unwrap();
+}
+
+// Read past the agent's other messages to its reply of `message_type`
+async fn next_message(socket: &mut WebSocketStream<tokio::net::TcpStream>, message_type: &str) -> Value {
+    while let Some(Ok(message)) = socket.next().await {
+        let Message::Text(text) = message else {
+            continue;
+        };
+        let message: Value = serde_json::from_str(&text).unwrap();
+        if message["message_type"] == message_type {
+            return message;
+        }
+    }
+    panic!("connection ended without a {} message", message_type);
+}
+
+#[tokio::test]
+async fn hung_local_app_is_reported_within_the_local_timeout() {
+    // A local app that accepts connections and never answers
+    let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = local.accept().await {
+            connections.push(stream);
+        }
+    });
+    let gateway = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tunnel = format!("{}=http://{}", TUNNEL_ID, local_addr);
+    let _agent = start_agent(
+        gateway.local_addr().unwrap(),
+        &["--tunnel-id", &tunnel, "--local-timeout", "1", "--local-retries", "2"],
+    );
+    let mut socket = accept_agent(&gateway).await;
+
+    let sent = Instant::now();
+    send_request(&mut socket, "GET", "/slow").await;
+    let error = tokio::time::timeout(Duration::from_secs(5), next_message(&mut socket, "error")).await.unwrap();
+
+    // Retries share the one --local-timeout instead of each getting their own, so the gateway
+    // hears about it before its own request timeout
+    assert!(sent.elapsed() < Duration::from_millis(1800), "error took {:?}", sent.elapsed());
+    assert_eq!(error["payload"], "Local server did not respond within 1s");
+    assert_eq!(error["request_id"], "test-request");
+}
+
+#[tokio::test]
+async fn ws_compress_exchanges_gzipped_payloads() {
+    let gateway = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let _agent = start_agent(gateway.local_addr().unwrap(), &["--tunnel-id", TUNNEL_ID, "--echo", "--ws-compress"]);
+    let welcome = json!({ "connection_id": "test-connection", "server_version": "0.1.0", "compression": "gzip" });
+    let (mut socket, handshake) = accept_agent_with(&gateway, welcome).await;
+    assert_eq!(handshake["compression"], "gzip");
+
+    // A request as a gateway running with --ws-compress sends it
+    let body = "compressible ".repeat(1000);
+    let request = json!({ "method": "POST", "path": "/upload", "body": body, "headers": [], "request_id": "test-request" });
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(request.to_string().as_bytes()).unwrap();
+    let message = json!({
+        "message_type": "request",
+        "payload": BASE64.encode(encoder.finish().unwrap()),
+        "compressed": true,
+    });
+    socket.send(Message::Text(message.to_string())).await.unwrap();
+
+    let response = tokio::time::timeout(Duration::from_secs(5), next_message(&mut socket, "response")).await.unwrap();
+    assert_eq!(response["compressed"], true);
+    let gzipped = BASE64.decode(response["payload"].as_str().unwrap()).unwrap();
+    let mut payload = String::new();
+    GzDecoder::new(gzipped.as_slice()).read_to_string(&mut payload).unwrap();
+    let payload: Value = serde_json::from_str(&payload).unwrap();
+    let echoed: Value = serde_json::from_str(payload["data"]["body"].as_str().unwrap()).unwrap();
+    assert_eq!(echoed["body"], body);
+}
@@ -0,0 +1,48 @@
+use std::{
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Embed the git commit and build time so /version can report exactly which build is running.
+// GATEWAY_GIT_HASH overrides the commit for builds outside a git checkout (e.g. Docker images).
+fn main() {
+    let git_hash = std::env::var("GATEWAY_GIT_HASH")
+        .ok()
+        .filter(|hash| !hash.is_empty())
+        .or_else(git_commit)
+        .unwrap_or_else(|| "unknown".to_string());
+    let build_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    println!("cargo:rustc-env=GATEWAY_GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=GATEWAY_BUILD_TIMESTAMP={}", build_timestamp);
+
+    // Rebuild on source changes as well as new commits
+    println!("cargo:rerun-if-env-changed=GATEWAY_GIT_HASH");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed=src");
+    for git_path in [".git/HEAD", ".git/refs/heads"] {
+        if Path::new(git_path).exists() {
+            println!("cargo:rerun-if-changed={}", git_path);
+        }
+    }
+}
+
+// Short hash of HEAD, with a "-dirty" suffix when the working tree has uncommitted changes
+fn git_commit() -> Option<String> {
+    let output = Command::new("git").args(["rev-parse", "--short", "HEAD"]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let hash = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    let dirty = Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .map(|status| !status.stdout.is_empty())
+        .unwrap_or(false);
+    Some(if dirty { format!("{}-dirty", hash) } else { hash })
+}
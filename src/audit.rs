@@ -0,0 +1,71 @@
+use hyper::StatusCode;
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    sync::Mutex,
+    time::{Instant, SystemTime},
+};
+use tracing::warn;
+
+// Destination for audit records: stdout or a file opened for appending
+pub struct AuditLog {
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+// One line of the audit trail
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: u64,
+    method: &'a str,
+    path: &'a str,
+    tunnel_id: Option<&'a str>,
+    status: u16,
+    duration_ms: u64,
+}
+
+impl AuditLog {
+    // "-" writes to stdout, anything else is treated as a file path
+    pub fn open(target: &str) -> io::Result<Self> {
+        let sink: Box<dyn Write + Send> = if target == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(target)?)
+        };
+        Ok(AuditLog { sink: Mutex::new(sink) })
+    }
+}
+
+// Append a JSON line describing a forwarded request once its response is resolved.
+// Does nothing when auditing is disabled.
+pub fn record(
+    log: Option<&AuditLog>,
+    method: &str,
+    path: &str,
+    tunnel_id: Option<&str>,
+    status: StatusCode,
+    started: Instant,
+) {
+    let Some(log) = log else {
+        return;
+    };
+    let record = AuditRecord {
+        timestamp: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        method,
+        path,
+        tunnel_id,
+        status: status.as_u16(),
+        duration_ms: started.elapsed().as_millis() as u64,
+    };
+    let Ok(line) = serde_json::to_string(&record) else {
+        return;
+    };
+
+    let mut sink = log.sink.lock().unwrap();
+    if let Err(e) = writeln!(sink, "{}", line).and_then(|_| sink.flush()) {
+        warn!("Failed to write audit record: {}", e);
+    }
+}
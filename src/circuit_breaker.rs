@@ -0,0 +1,134 @@
+use serde::Serialize;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+// Position of an agent's circuit breaker, as shown on /connections
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    // Requests flow normally
+    Closed,
+    // Too many consecutive failures: the agent is skipped until the cooldown ends
+    Open,
+    // Cooldown over: one probe request is let through to decide whether to close again
+    HalfOpen,
+}
+
+// Per-agent breaker that stops forwarding to an agent whose requests keep failing
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    connection_id: String,
+    // Consecutive failures that open the breaker; 0 disables it
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Instant,
+    // Set while the half-open probe is outstanding, so only one request tests the agent
+    probing: bool,
+    // Times the breaker has opened since the agent connected
+    opened_count: u64,
+}
+
+// Breaker fields reported on /connections and /admin/agents
+pub struct CircuitSnapshot {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub opened_count: u64,
+}
+
+impl CircuitBreaker {
+    pub fn new(connection_id: String, threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            connection_id,
+            threshold,
+            cooldown,
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: Instant::now(),
+                probing: false,
+                opened_count: 0,
+            }),
+        }
+    }
+
+    // Whether select_agent may pick this agent: closed, or ready for a probe
+    pub fn allows_requests(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => inner.opened_at.elapsed() >= self.cooldown,
+            CircuitState::HalfOpen => !inner.probing,
+        }
+    }
+
+    // A request was dispatched to the agent; once the cooldown is over it becomes the probe
+    pub fn dispatched(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == CircuitState::Open && inner.opened_at.elapsed() >= self.cooldown {
+            info!("Circuit for agent {} half-open, probing with the next request", self.connection_id);
+            inner.state = CircuitState::HalfOpen;
+        }
+        if inner.state == CircuitState::HalfOpen {
+            inner.probing = true;
+        }
+    }
+
+    // The agent answered a request successfully, closing the breaker
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.probing = false;
+        if inner.state != CircuitState::Closed {
+            info!("Circuit for agent {} closed", self.connection_id);
+            inner.state = CircuitState::Closed;
+        }
+    }
+
+    // The agent failed a request (error reply, bad response or timeout); a failed probe or the
+    // threshold-th failure in a row opens the breaker
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+        inner.probing = false;
+        let trips = match inner.state {
+            CircuitState::Closed => self.threshold != 0 && inner.consecutive_failures >= self.threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if trips {
+            warn!(
+                "Circuit for agent {} opened after {} consecutive failures, skipping it for {}s",
+                self.connection_id,
+                inner.consecutive_failures,
+                self.cooldown.as_secs()
+            );
+            inner.state = CircuitState::Open;
+            inner.opened_at = Instant::now();
+            inner.opened_count += 1;
+        }
+    }
+
+    // A request ended without an outcome (the client went away), so a probe may be retried
+    pub fn abandoned(&self) {
+        self.inner.lock().unwrap().probing = false;
+    }
+
+    pub fn snapshot(&self) -> CircuitSnapshot {
+        let inner = self.inner.lock().unwrap();
+        CircuitSnapshot {
+            state: inner.state,
+            consecutive_failures: inner.consecutive_failures,
+            opened_count: inner.opened_count,
+        }
+    }
+}
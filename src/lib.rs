@@ -0,0 +1,4351 @@
+use axum::{
+    extract::{rejection::JsonRejection, ConnectInfo, DefaultBodyLimit, Path, Query, Request, State},
+    middleware::{self, Next},
+    routing::{get, post},
+    Router,
+    response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Json},
+    extract::ws::{close_code, CloseFrame, WebSocket, WebSocketUpgrade, Message},
+    body::Body,
+};
+use clap::{Parser, ValueEnum};
+use futures::{stream::StreamExt, SinkExt};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+    io::{Read, Write as _},
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::{broadcast::{self, error::RecvError}, mpsc::{self, UnboundedSender}, Notify};
+use tracing::{field, info, info_span, warn, error, Instrument, Span};
+use uuid::Uuid;
+use serde::{Serialize, Deserialize};
+use axum::response::Response;
+use axum_server::tls_rustls::RustlsConfig;
+use hyper::{body::Frame, ext::ReasonPhrase, header::HeaderValue, HeaderMap, StatusCode};
+use http_body_util::{BodyExt as _, LengthLimitError, Limited, StreamBody};
+use dashmap::DashMap;
+use bytes::Bytes;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+mod audit;
+mod circuit_breaker;
+mod rate_limit;
+
+use circuit_breaker::{CircuitBreaker, CircuitState};
+
+use tower_http::compression::{
+    predicate::{DefaultPredicate, NotForContentType, Predicate},
+    CompressionLayer,
+};
+
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// Shared secret agents must present in their handshake
+    #[arg(long, env = "GATEWAY_AUTH_TOKEN", hide_env_values = true)]
+    auth_token: Option<String>,
+
+    /// Bearer token required by the /admin endpoints, which are open when it is unset
+    #[arg(long, env = "GATEWAY_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Seconds to wait for an agent to answer a forwarded request
+    #[arg(long, env = "GATEWAY_TIMEOUT_SECS", default_value_t = 30)]
+    request_timeout: u64,
+
+    /// Seconds shutdown waits for in-flight requests to finish and agents to disconnect
+    #[arg(long = "shutdown-grace-secs", env = "GATEWAY_SHUTDOWN_GRACE_SECS", default_value_t = 30)]
+    shutdown_grace: u64,
+
+    /// Seconds between pings sent to each agent
+    #[arg(long, env = "GATEWAY_PING_INTERVAL_SECS", default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    ping_interval: u64,
+
+    /// Seconds without a pong after which an agent is considered dead and evicted
+    #[arg(long, env = "GATEWAY_PONG_TIMEOUT_SECS", default_value_t = 90)]
+    pong_timeout: u64,
+
+    /// Seconds a new connection has to complete its handshake before it is closed
+    #[arg(long, env = "GATEWAY_HANDSHAKE_TIMEOUT_SECS", default_value_t = 10)]
+    handshake_timeout: u64,
+
+    /// Seconds without any traffic from an agent (requests, heartbeats or pings) before its connection is closed; 0 disables
+    #[arg(long, env = "GATEWAY_IDLE_TIMEOUT_SECS", default_value_t = 0)]
+    idle_timeout: u64,
+
+    /// Consecutive failed or timed-out requests after which an agent is skipped for --circuit-cooldown; 0 disables
+    #[arg(long, env = "GATEWAY_CIRCUIT_THRESHOLD", default_value_t = 5)]
+    circuit_threshold: u32,
+
+    /// Seconds an agent's open circuit skips it before a single probe request is let through
+    #[arg(long, env = "GATEWAY_CIRCUIT_COOLDOWN_SECS", default_value_t = 30)]
+    circuit_cooldown: u64,
+
+    /// Reject agents reporting a version lower than this (semver)
+    #[arg(long, env = "GATEWAY_MIN_AGENT_VERSION")]
+    min_agent_version: Option<semver::Version>,
+
+    /// Maximum /forward request body size in bytes (larger bodies get 413)
+    #[arg(long, env = "GATEWAY_MAX_BODY_SIZE", default_value_t = 10 * 1024 * 1024)]
+    max_body_size: usize,
+
+    /// Maximum number of simultaneous agent WebSocket connections
+    #[arg(long, env = "GATEWAY_MAX_CONNECTIONS", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    max_connections: u64,
+
+    /// Forwarded requests an agent may have outstanding before it is skipped (0 for no limit)
+    #[arg(long, env = "GATEWAY_MAX_IN_FLIGHT_PER_AGENT", default_value_t = 0)]
+    max_in_flight_per_agent: usize,
+
+    /// Messages queued for an agent's socket before further requests to it are shed with 503
+    #[arg(long, env = "GATEWAY_AGENT_QUEUE_CAPACITY", default_value_t = 256, value_parser = parse_queue_capacity)]
+    agent_queue_capacity: usize,
+
+    /// Largest WebSocket message or frame accepted from an agent, in bytes (larger ones close the connection)
+    #[arg(long, env = "GATEWAY_MAX_MESSAGE_SIZE", default_value_t = 16 * 1024 * 1024)]
+    max_message_size: usize,
+
+    /// Largest handshake accepted from a new agent, in bytes (larger ones close the connection)
+    #[arg(long, env = "GATEWAY_MAX_HANDSHAKE_SIZE", default_value_t = 4096)]
+    max_handshake_size: usize,
+
+    /// Gzip large request and response payloads inside messages (a protocol extension, not permessage-deflate) for agents that ask for it with their own --ws-compress
+    #[arg(long, env = "GATEWAY_WS_COMPRESS")]
+    ws_compress: bool,
+
+    /// JSON file overriding request_timeout, max_connections and allowed_tunnels and setting
+    /// html_replacements, re-read by POST /admin/reload
+    #[arg(long, env = "GATEWAY_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// File listing permitted tunnel IDs or tunnel UUID prefixes, one per line
+    #[arg(long, env = "GATEWAY_TUNNEL_ALLOWLIST_FILE")]
+    tunnel_allowlist_file: Option<PathBuf>,
+
+    /// Comma-separated permitted tunnel IDs or tunnel UUID prefixes
+    #[arg(long, env = "GATEWAY_ALLOWED_TUNNELS", value_delimiter = ',')]
+    allowed_tunnels: Vec<String>,
+
+    /// Milliseconds a request waits for an agent to (re)connect before failing with "No agents available"
+    #[arg(long, env = "GATEWAY_AGENT_WAIT_MS", default_value_t = 0)]
+    agent_wait_ms: u64,
+
+    /// Maximum simultaneous agents per tunnel purpose, e.g. web=5,api=2 (unlisted purposes are unlimited)
+    #[arg(long = "purpose-quota", env = "GATEWAY_PURPOSE_QUOTAS", value_delimiter = ',', value_parser = parse_purpose_quota)]
+    purpose_quotas: Vec<(String, usize)>,
+
+    /// Write a JSON audit line for every forwarded request to this file ("-" for stdout)
+    #[arg(long, env = "GATEWAY_AUDIT_LOG")]
+    audit_log: Option<String>,
+
+    /// Requests per second each client IP may send to the forwarding endpoints (unlimited when unset)
+    #[arg(long, env = "GATEWAY_RATE_LIMIT", value_parser = rate_limit::parse_rate)]
+    rate_limit: Option<f64>,
+
+    /// Requests a client IP may send back to back before --rate-limit applies
+    #[arg(long, env = "GATEWAY_RATE_LIMIT_BURST", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit_burst: u32,
+
+    /// Content type for direct GET responses by path extension, e.g. css=text/css (overrides the built-in table)
+    #[arg(long = "content-type", env = "GATEWAY_CONTENT_TYPES", value_delimiter = ',', value_parser = parse_content_type)]
+    content_types: Vec<(String, String)>,
+
+    /// Client headers not forwarded to agents, replacing the default list (Connection, Transfer-Encoding,
+    /// Content-Length and the other framing headers are dropped either way)
+    #[arg(
+        long = "strip-header",
+        env = "GATEWAY_STRIP_HEADERS",
+        value_delimiter = ',',
+        value_parser = parse_header_name,
+        default_values = HOP_BY_HOP_HEADERS
+    )]
+    strip_headers: Vec<String>,
+
+    /// Path prefix for the gateway's own routes (e.g. /__gateway serves /__gateway/health and
+    /// /__gateway/ws), leaving every other path to the tunnel; empty mounts them at the root
+    #[arg(long, env = "GATEWAY_ROUTE_PREFIX", default_value = "", value_parser = parse_route_prefix)]
+    route_prefix: String,
+
+    /// Path agents connect to for their WebSocket, e.g. /tunnel/connect behind a shared ingress
+    /// (under --route-prefix when one is set)
+    #[arg(long, env = "GATEWAY_WS_PATH", default_value = "/ws", value_parser = parse_ws_path)]
+    ws_path: String,
+
+    /// HTML page served with 503 to browsers on direct requests when no agent is available
+    #[arg(long, env = "GATEWAY_MAINTENANCE_PAGE")]
+    maintenance_page: Option<PathBuf>,
+
+    /// PEM certificate chain for serving HTTPS (requires --tls-key)
+    #[arg(long, env = "GATEWAY_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for serving HTTPS (requires --tls-cert)
+    #[arg(long, env = "GATEWAY_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// JSON file remembering recently active tunnel IDs across restarts
+    #[arg(long, env = "GATEWAY_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Refuse handshakes from tunnel IDs that aren't remembered in --state-file
+    #[arg(long, env = "GATEWAY_REQUIRE_KNOWN_TUNNEL", requires = "state_file")]
+    require_known_tunnel: bool,
+
+    /// Log output format
+    #[arg(long, value_enum, env = "GATEWAY_LOG_FORMAT", default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+// Parse a queue capacity, which tokio's bounded channels require to be at least 1
+fn parse_queue_capacity(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) => Err("capacity must be at least 1".to_string()),
+        Ok(capacity) => Ok(capacity),
+        Err(e) => Err(format!("invalid capacity '{}': {}", value, e)),
+    }
+}
+
+// Parse a `PURPOSE=COUNT` quota
+fn parse_purpose_quota(value: &str) -> Result<(String, usize), String> {
+    let (purpose, limit) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected PURPOSE=COUNT, got '{}'", value))?;
+    let limit = limit
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid quota for '{}': {}", purpose, e))?;
+    Ok((purpose.trim().to_string(), limit))
+}
+
+// Parse an `EXTENSION=CONTENT-TYPE` mapping; the extension is matched case-insensitively
+fn parse_content_type(value: &str) -> Result<(String, String), String> {
+    let (extension, content_type) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected EXTENSION=CONTENT-TYPE, got '{}'", value))?;
+    let extension = extension.trim().trim_start_matches('.').to_ascii_lowercase();
+    let content_type = content_type.trim();
+    if extension.is_empty() || HeaderValue::from_str(content_type).is_err() {
+        return Err(format!("invalid content type mapping '{}'", value));
+    }
+    Ok((extension, content_type.to_string()))
+}
+
+// Parse a header name for --strip-header, lowercased to compare with HeaderMap names
+fn parse_header_name(value: &str) -> Result<String, String> {
+    hyper::header::HeaderName::from_bytes(value.trim().as_bytes())
+        .map(|name| name.as_str().to_string())
+        .map_err(|_| format!("invalid header name '{}'", value))
+}
+
+// Parse a --route-prefix: a path starting with `/` made of plain segments, stored without a trailing
+// slash (so `/` is the same as no prefix)
+fn parse_route_prefix(value: &str) -> Result<String, String> {
+    let prefix = value.trim().trim_end_matches('/');
+    if !prefix.is_empty() && !is_plain_path(prefix) {
+        return Err(format!("invalid route prefix '{}', expected a path such as /__gateway", value));
+    }
+    Ok(prefix.to_string())
+}
+
+// Parse a --ws-path, which like a route prefix is made of plain segments but can't be empty
+fn parse_ws_path(value: &str) -> Result<String, String> {
+    let path = value.trim().trim_end_matches('/');
+    if !is_plain_path(path) {
+        return Err(format!("invalid WebSocket path '{}', expected a path such as /ws", value));
+    }
+    Ok(path.to_string())
+}
+
+// Whether a path is `/` followed by one or more segments of unreserved characters, with no
+// dot segments, so it can be mounted as a route as-is
+fn is_plain_path(path: &str) -> bool {
+    path.strip_prefix('/').is_some_and(|rest| {
+        rest.split('/').all(|segment| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && segment.chars().all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c))
+        })
+    })
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Serialize)]
+struct ApiResponse<T> {
+    status: String,
+    message: String,
+    // Set on errors clients may want to branch on without parsing the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<T>,
+}
+
+// Machine-readable reason for an error ApiResponse
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum ErrorCode {
+    // The request body was malformed or too large
+    InvalidRequest,
+    RateLimited,
+    Draining,
+    // No agent matched the request, or all of them were busy or unhealthy
+    NoAgents,
+    // No connected agent serves the requested X-Tunnel-Purpose
+    UnknownPurpose,
+    // Every agent the request could go to declared that it doesn't serve the path
+    PathNotServed,
+    // Agents serve the path, but declared that they don't accept the request's method
+    MethodNotAllowed,
+    // The request could not be handed to the agent's connection
+    SendFailed,
+    // The agent's outgoing queue was full, so the request was shed
+    AgentQueueFull,
+    // Agents could take the request, but all of them are at --max-in-flight-per-agent
+    AgentsSaturated,
+    // The agent replied with an error, e.g. its local app was unreachable
+    AgentError,
+    AgentTimeout,
+    // The agent disconnected before replying
+    AgentLost,
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    version: &'static str,
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+struct VersionResponse {
+    version: &'static str,
+    // Set by build.rs; "unknown" when built outside a git checkout without GATEWAY_GIT_HASH
+    git_hash: &'static str,
+    // Unix seconds when the binary was built
+    build_timestamp: u64,
+}
+
+#[derive(Serialize)]
+struct ReadyResponse {
+    ready_agents: usize,
+}
+
+#[derive(Serialize)]
+struct ReloadResponse {
+    request_timeout_secs: u64,
+    max_connections: usize,
+    // Number of allowlist entries, or null when any well-formed tunnel may register
+    allowlist_entries: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct DrainResponse {
+    draining: bool,
+    in_flight_requests: usize,
+}
+
+#[derive(Serialize)]
+struct ConnectionInfo {
+    connection_id: String,
+    connected_at: u64,
+    uptime_secs: u64,
+    last_activity_at: u64,
+    tunnel_id: Option<String>,
+    local_healthy: bool,
+    previous_connection_id: Option<String>,
+    labels: BTreeMap<String, String>,
+    local_url: Option<String>,
+    routes: BTreeMap<String, String>,
+    allowed_methods: Vec<String>,
+    allowed_paths: Vec<String>,
+    concurrency: usize,
+    in_flight_requests: usize,
+    request_count: u64,
+    error_count: u64,
+    circuit_state: CircuitState,
+    consecutive_failures: u32,
+    circuit_opened_count: u64,
+}
+
+// Everything known about one agent connection, for admin UIs (GET /admin/agents)
+#[derive(Serialize)]
+struct AgentDetails {
+    connection_id: String,
+    // None until the agent completes its handshake
+    tunnel_id: Option<String>,
+    purpose: Option<String>,
+    agent_version: Option<String>,
+    labels: BTreeMap<String, String>,
+    local_url: Option<String>,
+    routes: BTreeMap<String, String>,
+    allowed_methods: Vec<String>,
+    allowed_paths: Vec<String>,
+    connected_at: u64,
+    last_activity_at: u64,
+    local_healthy: bool,
+    previous_connection_id: Option<String>,
+    concurrency: usize,
+    in_flight_requests: usize,
+    request_count: u64,
+    error_count: u64,
+    circuit_state: CircuitState,
+    consecutive_failures: u32,
+    circuit_opened_count: u64,
+}
+
+// Connection counts for dashboards
+#[derive(Serialize)]
+struct ConnectionSummary {
+    total: usize,
+    handshaked: usize,
+    // Connected but without a valid handshake yet
+    pending: usize,
+    // Handshaked agents per tunnel purpose
+    by_purpose: BTreeMap<String, usize>,
+    // Requests waiting for an agent's response, across all connections
+    queued_requests: usize,
+}
+
+// The agent that would serve a request right now, for debugging routing (GET /resolve)
+#[derive(Serialize)]
+struct ResolvedAgent {
+    connection_id: String,
+    tunnel_id: String,
+    // Agents the request's purpose and labels allow that can take it now, rotated between
+    candidates: usize,
+}
+
+// Connection lifecycle change streamed to GET /events subscribers
+#[derive(Clone, Debug, Serialize)]
+struct ConnectionEvent {
+    // "connected", "handshaked" or "disconnected"
+    event: &'static str,
+    connection_id: String,
+    // Known once the agent has handshaked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnel_id: Option<String>,
+    timestamp: u64,
+}
+
+// A tunnel seen recently, as stored in the state file
+#[derive(Serialize, Deserialize)]
+struct KnownTunnel {
+    tunnel_id: String,
+    // Unix seconds of the last handshake or disconnect
+    last_seen: u64,
+}
+
+#[derive(Serialize)]
+struct TunnelInfo {
+    tunnel_id: String,
+    last_seen: u64,
+    connected: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct WebSocketMessage {
+    message_type: String,
+    payload: String,
+    // Position of a "response_chunk" within a streamed body, or the chunk count on "response_end"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
+    // Numbers agents give every message they send, checked for gaps and reordering. Older agents
+    // leave it out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message_seq: Option<u64>,
+    // The request a "response", "response_chunk", "response_end" or "error" answers. Older agents
+    // leave it out and answer requests one at a time, in order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    // The payload is gzipped and base64 encoded (--ws-compress)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    compressed: bool,
+}
+
+// Encode an outgoing message as JSON. The gateway's own types always serialize, so a failure is a
+// bug: it is logged and the caller drops the message or answers 500 instead of panicking its task
+fn encode_json<T: Serialize>(value: &T) -> Option<String> {
+    serde_json::to_string(value)
+        .map_err(|e| error!("Failed to encode message: {}", e))
+        .ok()
+}
+
+impl WebSocketMessage {
+    fn new(message_type: &str, payload: String) -> Self {
+        WebSocketMessage {
+            message_type: message_type.to_string(),
+            payload,
+            sequence: None,
+            message_seq: None,
+            request_id: None,
+            compressed: false,
+        }
+    }
+
+    // A message whose payload is `value` encoded as JSON
+    fn with_json<T: Serialize>(message_type: &str, value: &T) -> Option<Self> {
+        encode_json(value).map(|payload| Self::new(message_type, payload))
+    }
+
+    // The text frame carrying this message to the agent
+    fn to_frame(&self) -> Option<Message> {
+        encode_json(self).map(Message::Text)
+    }
+
+    // Gzip the payload if it is large enough to be worth it
+    fn compress(mut self) -> Self {
+        if self.payload.len() < COMPRESS_MIN_SIZE {
+            return self;
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        if let Ok(gzipped) = encoder.write_all(self.payload.as_bytes()).and_then(|()| encoder.finish()) {
+            self.payload = BASE64.encode(gzipped);
+            self.compressed = true;
+        }
+        self
+    }
+
+    // Restore a compressed payload, refusing one that inflates beyond `limit` bytes
+    fn decompress(mut self, limit: usize) -> Result<Self, String> {
+        if !self.compressed {
+            return Ok(self);
+        }
+        let gzipped = BASE64.decode(self.payload.as_bytes()).map_err(|e| format!("invalid base64: {}", e))?;
+        let mut payload = String::new();
+        GzDecoder::new(gzipped.as_slice())
+            .take(limit as u64 + 1)
+            .read_to_string(&mut payload)
+            .map_err(|e| format!("invalid gzip payload: {}", e))?;
+        if payload.len() > limit {
+            return Err(format!("payload inflates beyond the {} byte limit", limit));
+        }
+        self.payload = payload;
+        self.compressed = false;
+        Ok(self)
+    }
+}
+
+// What the receive task hands to a waiting forward handler
+#[derive(Debug)]
+enum AgentReply {
+    // The agent's AgentResponse; when data.streamed is true the body follows as chunks
+    Response(serde_json::Value),
+    Chunk(Bytes),
+    // The streamed body is complete, with any trailers that followed it
+    End(HeaderMap),
+    // The agent could not serve the request (e.g. the local app is unreachable)
+    Error(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ForwardedRequest {
+    method: String,
+    path: String,
+    body: String,
+    // The body is base64, for multipart uploads and other bodies that aren't UTF-8 text
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    binary: bool,
+    headers: Vec<(String, String)>,
+    // The client sent the body without a Content-Length (chunked on HTTP/1.1), so the local app
+    // should get it the same way
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    chunked: bool,
+    // Trailer fields that followed the body (/forward/raw only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    trailers: Vec<(String, String)>,
+    // Named by a "cancel" message if the request is abandoned before the agent replies, or the
+    // client goes away while the response is streaming
+    #[serde(default)]
+    request_id: String,
+}
+
+// Payload of "welcome": the first message on every agent connection
+#[derive(Serialize)]
+struct Welcome {
+    connection_id: String,
+    server_version: &'static str,
+    // "gzip" under --ws-compress, so agents asking for it compress their replies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<&'static str>,
+}
+
+// Payload of "health": the agent's periodic probe found its local app's health changed
+#[derive(Deserialize)]
+struct HealthReport {
+    healthy: bool,
+}
+
+// Payload of "ws_open": the agent should open a WebSocket to the local app for this stream
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TunnelOpen {
+    stream_id: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+// Payload of "ws_frame": one data frame relayed in either direction (binary frames are base64)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TunnelFrame {
+    stream_id: String,
+    data: String,
+    #[serde(default)]
+    binary: bool,
+}
+
+// Payload of "ws_close": either side finished the stream
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TunnelClose {
+    stream_id: String,
+    #[serde(default)]
+    code: Option<u16>,
+    #[serde(default)]
+    reason: String,
+}
+
+// A client WebSocket relayed through an agent connection
+struct TunnelStream {
+    connection_id: String,
+    client: UnboundedSender<Message>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AgentHandshake {
+    tunnel_id: String,
+    agent_version: String,
+    #[serde(default)]
+    auth_token: Option<String>,
+    // Older agents don't probe their local app and are assumed healthy
+    #[serde(default)]
+    local_healthy: Option<bool>,
+    // Set when the agent is reconnecting, linking this connection to its last one
+    #[serde(default)]
+    previous_connection_id: Option<String>,
+    // Arbitrary key/value tags (e.g. env=staging) that clients can select agents by
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    // The agent's fallback local URL and its --route prefix to target mappings, for operators.
+    // Older agents don't report them
+    #[serde(default)]
+    local_url: Option<String>,
+    #[serde(default)]
+    routes: BTreeMap<String, String>,
+    // Requests the agent handles at once (its --concurrency); older agents handle one at a time
+    #[serde(default)]
+    concurrency: Option<usize>,
+    // Methods and path patterns the agent serves (its --allow-method and --allow-path); empty
+    // lists, and older agents, accept everything
+    #[serde(default)]
+    allowed_methods: Vec<String>,
+    #[serde(default)]
+    allowed_paths: Vec<String>,
+    // "gzip" from agents started with --ws-compress
+    #[serde(default)]
+    compression: Option<String>,
+}
+
+// Connection details
+#[derive(Debug)]
+struct ConnectionDetails {
+    connected_at: u64,
+    // Unix seconds of the last frame received from the agent, updated by the receive task
+    last_activity: Arc<AtomicU64>,
+    tunnel_id: Option<String>,
+    // Whether the agent's local app passed its health probe at handshake time
+    local_healthy: bool,
+    // The agent's connection before it reconnected, as reported in its handshake
+    previous_connection_id: Option<String>,
+    // Labels from the agent's handshake
+    labels: BTreeMap<String, String>,
+    // Version the agent reported in its handshake
+    agent_version: Option<String>,
+    // Local URL and routes the agent reported in its handshake
+    local_url: Option<String>,
+    routes: BTreeMap<String, String>,
+    // Methods (uppercase) and path patterns from the agent's handshake; requests outside them
+    // are never sent to it
+    allowed_methods: Vec<String>,
+    allowed_paths: Vec<String>,
+    // Forwarded requests awaiting a response from this agent
+    in_flight: Arc<AtomicUsize>,
+    // Requests forwarded to this agent, and how many of them failed, since it connected
+    request_count: Arc<AtomicU64>,
+    error_count: Arc<AtomicU64>,
+    // Skips the agent after too many consecutive failures (--circuit-threshold)
+    circuit: Arc<CircuitBreaker>,
+    // Set while the agent streams a response body. An agent handling one request at a time
+    // can't take another meanwhile
+    streaming: Arc<AtomicBool>,
+    // Requests the agent reported it handles at once
+    concurrency: usize,
+    // Bounded by --agent-queue-capacity, so an agent that stops reading can't grow it without limit
+    sender: mpsc::Sender<Message>,
+    // Both sides enabled --ws-compress, so large requests are sent gzipped
+    compress: bool,
+    // Forward handlers waiting for a reply from this agent, oldest first
+    response_handlers: Vec<ResponseHandler>,
+}
+
+// A forward handler waiting for the agent's reply to one request
+#[derive(Debug)]
+struct ResponseHandler {
+    request_id: String,
+    sender: mpsc::Sender<AgentReply>,
+    // Next response_chunk expected, once a streamed head has arrived
+    next_chunk: Option<u64>,
+}
+
+impl ResponseHandler {
+    fn new(request_id: &str, sender: mpsc::Sender<AgentReply>) -> Self {
+        ResponseHandler {
+            request_id: request_id.to_string(),
+            sender,
+            next_chunk: None,
+        }
+    }
+}
+
+impl ConnectionDetails {
+    // Whether the agent declared that it serves `path` (query string excluded): a pattern ending
+    // in '*' matches every path starting with the rest, any other only itself
+    fn serves_path(&self, path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or_default();
+        self.allowed_paths.is_empty()
+            || self.allowed_paths.iter().any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == pattern,
+            })
+    }
+
+    fn accepts_method(&self, method: &str) -> bool {
+        self.allowed_methods.is_empty() || self.allowed_methods.iter().any(|allowed| allowed == method)
+    }
+}
+
+// How often idle clients are dropped from the rate limiter
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Upper bound on how often connections are checked against --idle-timeout
+const IDLE_SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+// Under --ws-compress, payloads smaller than this are sent as they are: gzip and base64 would
+// barely shrink them
+const COMPRESS_MIN_SIZE: usize = 1024;
+
+// The only compression offered in the welcome and accepted in the handshake
+const COMPRESSION_GZIP: &str = "gzip";
+
+// Replies buffered per forwarded request; a request whose client falls this far behind is failed
+const RESPONSE_CHANNEL_CAPACITY: usize = 16;
+
+// Lifecycle events buffered per /events subscriber before a slow one starts missing them
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+// Gateway-wide request counters exposed on /metrics
+#[derive(Default)]
+struct Metrics {
+    forwarded_requests: AtomicU64,
+    request_failures: AtomicU64,
+    request_timeouts: AtomicU64,
+    replayed_requests: AtomicU64,
+    orphaned_responses: AtomicU64,
+    shed_requests: AtomicU64,
+}
+
+impl Metrics {
+    // Render the counters in the Prometheus text exposition format
+    fn render(&self, active_connections: usize, queued_requests: usize) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("gateway_forwarded_requests_total", "Total requests forwarded to agents", &self.forwarded_requests),
+            ("gateway_request_failures_total", "Total forwarded requests that failed", &self.request_failures),
+            ("gateway_request_timeouts_total", "Total forwarded requests that timed out waiting for an agent", &self.request_timeouts),
+            ("gateway_replayed_requests_total", "Total idempotent requests replayed on another agent after theirs disconnected", &self.replayed_requests),
+            ("gateway_orphaned_responses_total", "Total agent responses dropped because no request was waiting for them", &self.orphaned_responses),
+            ("gateway_shed_requests_total", "Total requests refused because their agent's outgoing queue was full", &self.shed_requests),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(out, "# HELP gateway_active_connections Currently open agent connections");
+        let _ = writeln!(out, "# TYPE gateway_active_connections gauge");
+        let _ = writeln!(out, "gateway_active_connections {}", active_connections);
+        let _ = writeln!(out, "# HELP gateway_queued_requests Requests currently waiting for an agent response");
+        let _ = writeln!(out, "# TYPE gateway_queued_requests gauge");
+        let _ = writeln!(out, "gateway_queued_requests {}", queued_requests);
+        out
+    }
+}
+
+impl ConnectionInfo {
+    fn new(connection_id: &str, details: &ConnectionDetails) -> Self {
+        let circuit = details.circuit.snapshot();
+        ConnectionInfo {
+            connection_id: connection_id.to_string(),
+            connected_at: details.connected_at,
+            uptime_secs: unix_timestamp().saturating_sub(details.connected_at),
+            last_activity_at: details.last_activity.load(Ordering::Relaxed),
+            tunnel_id: details.tunnel_id.clone(),
+            local_healthy: details.local_healthy,
+            previous_connection_id: details.previous_connection_id.clone(),
+            labels: details.labels.clone(),
+            local_url: details.local_url.clone(),
+            routes: details.routes.clone(),
+            allowed_methods: details.allowed_methods.clone(),
+            allowed_paths: details.allowed_paths.clone(),
+            concurrency: details.concurrency,
+            in_flight_requests: details.in_flight.load(Ordering::SeqCst),
+            request_count: details.request_count.load(Ordering::Relaxed),
+            error_count: details.error_count.load(Ordering::Relaxed),
+            circuit_state: circuit.state,
+            consecutive_failures: circuit.consecutive_failures,
+            circuit_opened_count: circuit.opened_count,
+        }
+    }
+}
+
+impl AgentDetails {
+    fn new(connection_id: &str, details: &ConnectionDetails) -> Self {
+        let circuit = details.circuit.snapshot();
+        AgentDetails {
+            connection_id: connection_id.to_string(),
+            tunnel_id: details.tunnel_id.clone(),
+            purpose: details.tunnel_id.as_deref().and_then(tunnel_purpose).map(str::to_string),
+            agent_version: details.agent_version.clone(),
+            labels: details.labels.clone(),
+            local_url: details.local_url.clone(),
+            routes: details.routes.clone(),
+            allowed_methods: details.allowed_methods.clone(),
+            allowed_paths: details.allowed_paths.clone(),
+            connected_at: details.connected_at,
+            last_activity_at: details.last_activity.load(Ordering::Relaxed),
+            local_healthy: details.local_healthy,
+            previous_connection_id: details.previous_connection_id.clone(),
+            concurrency: details.concurrency,
+            in_flight_requests: details.in_flight.load(Ordering::SeqCst),
+            request_count: details.request_count.load(Ordering::Relaxed),
+            error_count: details.error_count.load(Ordering::Relaxed),
+            circuit_state: circuit.state,
+            consecutive_failures: circuit.consecutive_failures,
+            circuit_opened_count: circuit.opened_count,
+        }
+    }
+}
+
+// The reloadable part of the configuration. Each request or handshake takes one snapshot,
+// so a reload never mixes old and new values within it.
+#[derive(Debug)]
+struct RuntimeConfig {
+    // How long forward handlers wait for an agent response (GATEWAY_TIMEOUT_SECS)
+    request_timeout: Duration,
+    max_connections: usize,
+    // Tunnel IDs or UUID prefixes allowed to register; None admits any well-formed tunnel
+    tunnel_allowlist: Option<Vec<String>>,
+    // Find/replace pairs applied in order to buffered text/html direct responses
+    html_replacements: Vec<(String, String)>,
+}
+
+// Contents of the --config file; settings it leaves out keep their command line or env value
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    request_timeout: Option<u64>,
+    max_connections: Option<usize>,
+    // Replaces --allowed-tunnels; --tunnel-allowlist-file entries still apply
+    allowed_tunnels: Option<Vec<String>>,
+    // [find, replace] pairs, e.g. to inject a script tag before </body>
+    #[serde(default)]
+    html_replacements: Vec<(String, String)>,
+}
+
+// Build the runtime configuration from the startup args, the --config file and the allowlist file
+fn load_runtime_config(args: &Args) -> Result<RuntimeConfig, String> {
+    let file = match &args.config {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read config file {}: {}", path.display(), e))?;
+            serde_json::from_str::<ConfigFile>(&contents)
+                .map_err(|e| format!("invalid config file {}: {}", path.display(), e))?
+        }
+        None => ConfigFile::default(),
+    };
+
+    let max_connections = file.max_connections.unwrap_or(args.max_connections as usize);
+    if max_connections == 0 {
+        return Err("max_connections must be at least 1".to_string());
+    }
+    if file.html_replacements.iter().any(|(find, _)| find.is_empty()) {
+        return Err("html_replacements must not have an empty find string".to_string());
+    }
+    let allowed_tunnels = file.allowed_tunnels.as_deref().unwrap_or(&args.allowed_tunnels);
+    let tunnel_allowlist = load_tunnel_allowlist(args.tunnel_allowlist_file.as_deref(), allowed_tunnels)
+        .map_err(|e| {
+            let path = args.tunnel_allowlist_file.as_deref().unwrap_or(FsPath::new(""));
+            format!("failed to read tunnel allowlist {}: {}", path.display(), e)
+        })?;
+
+    Ok(RuntimeConfig {
+        request_timeout: Duration::from_secs(file.request_timeout.unwrap_or(args.request_timeout)),
+        max_connections,
+        tunnel_allowlist,
+        html_replacements: file.html_replacements,
+    })
+}
+
+// Shared state between all connections using DashMap
+pub struct AppState {
+    connections: DashMap<String, ConnectionDetails>,
+    // Tunneled client WebSockets keyed by stream ID
+    tunnel_streams: DashMap<String, TunnelStream>,
+    metrics: Metrics,
+    // Open agent sockets, counted separately so the limit check doesn't lock every DashMap shard
+    connection_count: AtomicUsize,
+    // Outstanding forwarded requests per agent before select_agent skips it; 0 is unlimited
+    max_in_flight_per_agent: usize,
+    // Largest message accepted from an agent socket, enforced by the WebSocket codec
+    max_message_size: usize,
+    // Round-robin position used by select_agent
+    agent_cursor: AtomicUsize,
+    // Signalled on every successful handshake so requests waiting for an agent can retry
+    agent_available: Notify,
+    agent_wait: Duration,
+    // Set by POST /admin/drain or shutdown: new forward requests get 503 while in-flight ones finish
+    draining: AtomicBool,
+    in_flight_requests: AtomicUsize,
+    // Shared secret agents must present in their handshake (GATEWAY_AUTH_TOKEN)
+    auth_token: Option<String>,
+    // Bearer token for the /admin endpoints (GATEWAY_ADMIN_TOKEN)
+    admin_token: Option<String>,
+    // Liveness probing of agent sockets
+    ping_interval: Duration,
+    pong_timeout: Duration,
+    // Connections without a valid handshake after this long are reaped
+    handshake_timeout: Duration,
+    // Oldest agent release allowed to connect (GATEWAY_MIN_AGENT_VERSION)
+    min_agent_version: Option<semver::Version>,
+    // Maximum simultaneous agents per tunnel purpose; purposes not listed are unlimited
+    purpose_quotas: HashMap<String, usize>,
+    // Settings POST /admin/reload can change, rebuilt from these startup args and --config
+    config: RwLock<Arc<RuntimeConfig>>,
+    args: Args,
+    // Audit trail of forwarded requests (GATEWAY_AUDIT_LOG)
+    audit: Option<audit::AuditLog>,
+    // Per-client-IP limit on forwarded requests (GATEWAY_RATE_LIMIT)
+    rate_limiter: Option<rate_limit::RateLimiter>,
+    // Extension to Content-Type overrides for direct requests (GATEWAY_CONTENT_TYPES)
+    content_types: HashMap<String, String>,
+    // HTML shown instead of "No agents available" on direct requests (GATEWAY_MAINTENANCE_PAGE)
+    maintenance_page: Option<String>,
+    // Connection lifecycle events for GET /events; sending fails harmlessly with no subscribers
+    events: broadcast::Sender<ConnectionEvent>,
+    // Fired once shutdown starts, ending /events streams so they don't hold the server open
+    shutdown: broadcast::Sender<()>,
+    // Recently active tunnel IDs and when they were last seen, mirrored to state_file
+    known_tunnels: Arc<Mutex<BTreeMap<String, u64>>>,
+    state_file: Option<PathBuf>,
+    // Held while state_file is written, so writes for concurrent handshakes land one at a time
+    state_file_lock: Arc<Mutex<()>>,
+}
+
+impl AppState {
+    // Shared state for a gateway started with `args`. Loads the config file, tunnel allowlist, audit log,
+    // maintenance page and state file, failing if a configured one is unusable
+    pub fn new(args: Args) -> Result<Self, String> {
+        // Load the agent authentication secret
+        let auth_token = args.auth_token.clone().filter(|token| !token.is_empty());
+        if auth_token.is_none() {
+            warn!("GATEWAY_AUTH_TOKEN is not set, agent handshakes will not be authenticated");
+        }
+        let admin_token = args.admin_token.clone().filter(|token| !token.is_empty());
+        if admin_token.is_none() {
+            warn!("GATEWAY_ADMIN_TOKEN is not set, /admin endpoints are open to any client");
+        }
+
+        // Load the config file and tunnel allowlist; a configured but unreadable file is fatal rather
+        // than failing open
+        let config = match load_runtime_config(&args) {
+            Ok(config) => config,
+            Err(e) => return Err(format!("Failed to load configuration: {}", e)),
+        };
+        if let Some(allowlist) = &config.tunnel_allowlist {
+            info!("Tunnel allowlist enabled with {} entries", allowlist.len());
+        }
+
+        // Open the audit sink; like the allowlist, a configured but unusable sink is fatal
+        let audit = match args.audit_log.as_deref().map(audit::AuditLog::open).transpose() {
+            Ok(audit) => audit,
+            Err(e) => {
+                let path = args.audit_log.as_deref().unwrap_or_default();
+                return Err(format!("Failed to open audit log {}: {}", path, e));
+            }
+        };
+
+        // Load the maintenance page once; a configured but unreadable page is fatal
+        let maintenance_page = match args.maintenance_page.as_deref().map(std::fs::read_to_string).transpose() {
+            Ok(page) => page,
+            Err(e) => {
+                let path = args.maintenance_page.as_deref().unwrap_or(FsPath::new(""));
+                return Err(format!("Failed to read maintenance page {}: {}", path.display(), e));
+            }
+        };
+
+        // Restore the tunnels seen before the last restart
+        let known_tunnels = match &args.state_file {
+            Some(path) => {
+                let known = load_known_tunnels(path);
+                info!("Loaded {} known tunnels from {}", known.len(), path.display());
+                known
+            }
+            None => BTreeMap::new(),
+        };
+
+        Ok(AppState {
+            connections: DashMap::new(),
+            tunnel_streams: DashMap::new(),
+            metrics: Metrics::default(),
+            connection_count: AtomicUsize::new(0),
+            max_in_flight_per_agent: args.max_in_flight_per_agent,
+            max_message_size: args.max_message_size,
+            agent_cursor: AtomicUsize::new(0),
+            agent_available: Notify::new(),
+            agent_wait: Duration::from_millis(args.agent_wait_ms),
+            draining: AtomicBool::new(false),
+            in_flight_requests: AtomicUsize::new(0),
+            auth_token,
+            admin_token,
+            ping_interval: Duration::from_secs(args.ping_interval),
+            pong_timeout: Duration::from_secs(args.pong_timeout),
+            handshake_timeout: Duration::from_secs(args.handshake_timeout),
+            min_agent_version: args.min_agent_version.clone(),
+            purpose_quotas: args.purpose_quotas.iter().cloned().collect(),
+            config: RwLock::new(Arc::new(config)),
+            audit,
+            rate_limiter: args.rate_limit.map(|rate| rate_limit::RateLimiter::new(rate, args.rate_limit_burst)),
+            content_types: args.content_types.iter().cloned().collect(),
+            maintenance_page,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            shutdown: broadcast::channel(1).0,
+            known_tunnels: Arc::new(Mutex::new(known_tunnels)),
+            state_file: args.state_file.clone(),
+            state_file_lock: Arc::new(Mutex::new(())),
+            args,
+        })
+    }
+
+    // Snapshot of the current reloadable settings
+    fn config(&self) -> Arc<RuntimeConfig> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+}
+
+// Validate tunnel ID format
+fn validate_tunnel_id(tunnel_id: &str) -> bool {
+    // Format: agent_{uuid}_{purpose}
+    let parts: Vec<&str> = tunnel_id.split('_').collect();
+    if parts.len() != 3 {
+        return false;
+    }
+
+    if parts[0] != "agent" {
+        return false;
+    }
+
+    // Validate UUID part
+    if uuid::Uuid::parse_str(parts[1]).is_err() {
+        return false;
+    }
+
+    // Validate purpose (only allow alphanumeric and underscore)
+    parts[2].chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Read the tunnels remembered by a previous run; a missing file just means none
+fn load_known_tunnels(path: &FsPath) -> BTreeMap<String, u64> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(e) => {
+            warn!("Failed to read state file {}: {}", path.display(), e);
+            return BTreeMap::new();
+        }
+    };
+    match serde_json::from_str::<Vec<KnownTunnel>>(&contents) {
+        Ok(tunnels) => tunnels.into_iter().map(|tunnel| (tunnel.tunnel_id, tunnel.last_seen)).collect(),
+        Err(e) => {
+            warn!("Ignoring malformed state file {}: {}", path.display(), e);
+            BTreeMap::new()
+        }
+    }
+}
+
+// Write through a temporary file so a crash never leaves a half-written state file
+fn save_known_tunnels(path: &FsPath, known: &BTreeMap<String, u64>) -> std::io::Result<()> {
+    let tunnels: Vec<KnownTunnel> = known
+        .iter()
+        .map(|(tunnel_id, last_seen)| KnownTunnel { tunnel_id: tunnel_id.clone(), last_seen: *last_seen })
+        .collect();
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec_pretty(&tunnels)?)?;
+    std::fs::rename(tmp_path, path)
+}
+
+// Mark a tunnel as seen now and persist the list when a state file is configured. The file is
+// written on the blocking pool rather than under the known_tunnels lock; each write takes the list
+// as it is by then, so the last write always includes every tunnel remembered before it
+fn remember_tunnel(state: &AppState, tunnel_id: &str) {
+    state.known_tunnels.lock().unwrap().insert(tunnel_id.to_string(), unix_timestamp());
+    let Some(path) = state.state_file.clone() else {
+        return;
+    };
+    let known_tunnels = Arc::clone(&state.known_tunnels);
+    let state_file_lock = Arc::clone(&state.state_file_lock);
+    tokio::task::spawn_blocking(move || {
+        let _writing = state_file_lock.lock().unwrap();
+        let known = known_tunnels.lock().unwrap().clone();
+        if let Err(e) = save_known_tunnels(&path, &known) {
+            warn!("Failed to write state file {}: {}", path.display(), e);
+        }
+    });
+}
+
+// Whether a tunnel was remembered in the state file or has handshaked since startup
+fn is_known_tunnel(state: &AppState, tunnel_id: &str) -> bool {
+    state.known_tunnels.lock().unwrap().contains_key(tunnel_id)
+}
+
+// Purpose segment of a tunnel ID accepted by validate_tunnel_id
+fn tunnel_purpose(tunnel_id: &str) -> Option<&str> {
+    tunnel_id.splitn(3, '_').nth(2)
+}
+
+// Query parameter restricting a request to agents carrying a label, as `KEY:VALUE`
+const TUNNEL_LABEL_PARAM: &str = "tunnel_label";
+// Request header restricting a request to agents whose tunnel ID has this purpose
+const X_TUNNEL_PURPOSE: &str = "x-tunnel-purpose";
+// Response header on /forward and direct requests naming the agent that handled them
+const X_SERVED_BY: &str = "x-served-by";
+
+// The agent a request was dispatched to, for the audit log and X-Served-By
+struct ServedBy {
+    connection_id: String,
+    tunnel_id: Option<String>,
+}
+
+impl ServedBy {
+    fn new(connection_id: &str, details: &ConnectionDetails) -> Self {
+        ServedBy {
+            connection_id: connection_id.to_string(),
+            tunnel_id: details.tunnel_id.clone(),
+        }
+    }
+
+    // `<connection_id>; purpose=<purpose>`, leaving the purpose out if the tunnel ID has none
+    fn header_value(&self) -> HeaderValue {
+        let value = match self.tunnel_id.as_deref().and_then(tunnel_purpose) {
+            Some(purpose) => format!("{}; purpose={}", self.connection_id, purpose),
+            None => self.connection_id.clone(),
+        };
+        HeaderValue::from_str(&value).unwrap_or_else(|_| HeaderValue::from_static("unknown"))
+    }
+}
+
+// Add X-Served-By to a response from an agent that was dispatched to
+fn with_served_by(mut response: Response, served_by: Option<&ServedBy>) -> Response {
+    if let Some(served_by) = served_by {
+        response.headers_mut().insert(X_SERVED_BY, served_by.header_value());
+    }
+    response
+}
+
+// Constraints a request places on which agents may serve it
+#[derive(Debug, Default)]
+struct AgentFilter {
+    purpose: Option<String>,
+    // Every pair must be among the agent's handshake labels
+    labels: Vec<(String, String)>,
+    // Method and path forwarded to the agent, which must be among those it declared it accepts
+    request: Option<(String, String)>,
+}
+
+// Why no agent will take a request however long it waits, unlike agents that exist but are
+// busy or unhealthy (503)
+struct Rejection {
+    status: StatusCode,
+    code: ErrorCode,
+    message: String,
+    // Methods the agents accept for the path, sent as the Allow header of a 405
+    allow: Option<String>,
+}
+
+impl Rejection {
+    // As an ApiResponse, for /forward
+    fn into_api_response(self) -> Response<Body> {
+        let response = (
+            self.status,
+            Json(ApiResponse::<serde_json::Value> {
+                status: "error".to_string(),
+                message: self.message,
+                code: Some(self.code),
+                data: None,
+            }),
+        )
+            .into_response();
+        with_allow(response, self.allow)
+    }
+
+    // As JSON or plain text depending on the client's Accept header, for raw and direct requests
+    fn into_direct_response(self, wants_json: bool) -> Response<Body> {
+        with_allow(direct_error_response(self.status, self.message, wants_json), self.allow)
+    }
+}
+
+fn with_allow(mut response: Response<Body>, allow: Option<String>) -> Response<Body> {
+    if let Some(allow) = allow.and_then(|allow| HeaderValue::from_str(&allow).ok()) {
+        response.headers_mut().insert(hyper::header::ALLOW, allow);
+    }
+    response
+}
+
+impl AgentFilter {
+    // Collect the `tunnel_label=KEY:VALUE` parameters of a request's query string and its
+    // X-Tunnel-Purpose header; label values without a colon match agents that have the key
+    // with an empty value
+    fn from_request(query: &[(String, String)], headers: &HeaderMap) -> Self {
+        let purpose = headers
+            .get(X_TUNNEL_PURPOSE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|purpose| !purpose.is_empty())
+            .map(str::to_string);
+        let labels = query
+            .iter()
+            .filter(|(name, _)| name == TUNNEL_LABEL_PARAM)
+            .map(|(_, value)| {
+                let (key, value) = value.split_once(':').unwrap_or((value.as_str(), ""));
+                (key.to_string(), value.to_string())
+            })
+            .collect();
+        AgentFilter { purpose, labels, request: None }
+    }
+
+    // Also require agents to accept this method and path
+    fn for_request(mut self, method: &str, path: &str) -> Self {
+        self.request = Some((method.to_string(), path.to_string()));
+        self
+    }
+
+    // Why no connected agent could ever serve the request: a purpose none of them has (404), or
+    // agents that all declared they don't serve its path (404) or method (405). None when some
+    // agent could, once it is healthy or has a free slot
+    fn rejection(&self, state: &AppState) -> Option<Rejection> {
+        if let Some(purpose) = self.purpose.as_deref() {
+            let connected = state.connections
+                .iter()
+                .any(|entry| entry.value().tunnel_id.as_deref().and_then(tunnel_purpose) == Some(purpose));
+            if !connected {
+                return Some(Rejection {
+                    status: StatusCode::NOT_FOUND,
+                    code: ErrorCode::UnknownPurpose,
+                    message: unknown_purpose_message(purpose),
+                    allow: None,
+                });
+            }
+        }
+        let (method, path) = self.request.as_ref()?;
+        let mut routable = false;
+        let mut serves_path = false;
+        let mut allow = BTreeSet::new();
+        for entry in state.connections.iter() {
+            let Some(tunnel_id) = entry.value().tunnel_id.as_deref() else {
+                continue;
+            };
+            if !self.routes_to(tunnel_id, entry.value()) {
+                continue;
+            }
+            routable = true;
+            if entry.value().serves_path(path) {
+                if entry.value().accepts_method(method) {
+                    return None;
+                }
+                serves_path = true;
+                allow.extend(entry.value().allowed_methods.iter().cloned());
+            }
+        }
+        if !routable {
+            return None;
+        }
+        Some(if serves_path {
+            Rejection {
+                status: StatusCode::METHOD_NOT_ALLOWED,
+                code: ErrorCode::MethodNotAllowed,
+                message: format!("No agent accepts {} requests for {}", method, path),
+                allow: Some(allow.into_iter().collect::<Vec<_>>().join(", ")),
+            }
+        } else {
+            Rejection {
+                status: StatusCode::NOT_FOUND,
+                code: ErrorCode::PathNotServed,
+                message: format!("No agent serves {}", path),
+                allow: None,
+            }
+        })
+    }
+
+    // Whether an agent the request could go to was passed over only for being at
+    // --max-in-flight-per-agent, so the request failed for lack of capacity rather than of agents
+    fn saturated(&self, state: &AppState) -> bool {
+        state.max_in_flight_per_agent != 0
+            && state.connections.iter().any(|entry| {
+                entry.value().tunnel_id.as_deref().is_some_and(|tunnel_id| self.matches(tunnel_id, entry.value()))
+                    && entry.value().in_flight.load(Ordering::SeqCst) >= state.max_in_flight_per_agent
+            })
+    }
+
+    fn matches(&self, tunnel_id: &str, details: &ConnectionDetails) -> bool {
+        self.routes_to(tunnel_id, details)
+            && self.request.as_ref().is_none_or(|(method, path)| {
+                details.serves_path(path) && details.accepts_method(method)
+            })
+    }
+
+    // Whether the purpose and labels allow the agent, whatever the request
+    fn routes_to(&self, tunnel_id: &str, details: &ConnectionDetails) -> bool {
+        if self.purpose.as_deref().is_some_and(|purpose| tunnel_purpose(tunnel_id) != Some(purpose)) {
+            return false;
+        }
+        self.labels
+            .iter()
+            .all(|(key, value)| details.labels.get(key) == Some(value))
+    }
+}
+
+// A forwarded request outstanding on one agent, released when its handler finishes
+// (the response arrived, failed or timed out). Released before any reply, on a timeout or
+// because axum dropped the handler when the client disconnected, it tells the agent to
+// cancel the request, aborting a local call nobody is waiting for any more
+struct AgentSlot {
+    connection_id: String,
+    in_flight: Arc<AtomicUsize>,
+    error_count: Arc<AtomicU64>,
+    circuit: Arc<CircuitBreaker>,
+    streaming: Arc<AtomicBool>,
+    state: Arc<AppState>,
+    request_id: String,
+    sender: mpsc::Sender<Message>,
+    answered: bool,
+    failed: bool,
+    holds_stream: bool,
+}
+
+impl AgentSlot {
+    // Count a request forwarded to the agent and hold its in-flight slot
+    fn acquire(state: &Arc<AppState>, connection_id: &str, details: &ConnectionDetails) -> Self {
+        details.request_count.fetch_add(1, Ordering::Relaxed);
+        details.in_flight.fetch_add(1, Ordering::SeqCst);
+        details.circuit.dispatched();
+        AgentSlot {
+            connection_id: connection_id.to_string(),
+            in_flight: Arc::clone(&details.in_flight),
+            error_count: Arc::clone(&details.error_count),
+            circuit: Arc::clone(&details.circuit),
+            streaming: Arc::clone(&details.streaming),
+            state: Arc::clone(state),
+            request_id: Uuid::new_v4().to_string(),
+            sender: details.sender.clone(),
+            answered: false,
+            failed: false,
+            holds_stream: false,
+        }
+    }
+
+    // The agent replied (response, error or connection loss), so there is nothing to cancel
+    fn mark_answered(&mut self) {
+        self.answered = true;
+    }
+
+    // The reply was a streamed head, so the request stays open (and is cancelled if dropped) until
+    // the body ends, and an agent handling one request at a time takes no other meanwhile
+    fn mark_streaming(&mut self) {
+        self.answered = false;
+        self.holds_stream = true;
+        self.streaming.store(true, Ordering::SeqCst);
+    }
+
+    // Count a request the agent failed: an error reply, a bad or missing response, or a timeout
+    fn record_error(&mut self) {
+        self.error_count.fetch_add(1, Ordering::Relaxed);
+        self.circuit.record_failure();
+        self.failed = true;
+    }
+}
+
+impl Drop for AgentSlot {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        if self.holds_stream {
+            self.streaming.store(false, Ordering::SeqCst);
+        }
+        match (self.answered, self.failed) {
+            (_, true) => {}
+            (true, false) => self.circuit.record_success(),
+            (false, false) => self.circuit.abandoned(),
+        }
+        // Replies the agent still sends for this request are dropped as orphans
+        if let Some(mut conn) = self.state.connections.get_mut(&self.connection_id) {
+            conn.response_handlers.retain(|handler| handler.request_id != self.request_id);
+        }
+        if !self.answered {
+            let cancel = WebSocketMessage::new("cancel", self.request_id.clone());
+            if cancel.to_frame().is_some_and(|frame| self.sender.try_send(frame).is_ok()) {
+                info!("Cancelled unanswered request {} on the agent", self.request_id);
+            }
+        }
+        // The agent may have been skipped for being full, so wake requests waiting for one
+        self.state.agent_available.notify_waiters();
+    }
+}
+
+// Pick the next handshaked agent with a healthy local app, a closed circuit and spare capacity, rotating
+// through the agents that match the filter (or all agents for an empty filter)
+fn select_agent(state: &AppState, filter: &AgentFilter) -> Option<String> {
+    let mut candidates = eligible_agents(state, filter);
+    if candidates.is_empty() {
+        return None;
+    }
+    let index = state.agent_cursor.fetch_add(1, Ordering::Relaxed) % candidates.len();
+    Some(candidates.swap_remove(index))
+}
+
+// The agent select_agent would pick next and how many it rotates between, without moving the
+// rotation on
+fn peek_agent(state: &AppState, filter: &AgentFilter) -> Option<(String, usize)> {
+    let mut candidates = eligible_agents(state, filter);
+    if candidates.is_empty() {
+        return None;
+    }
+    let count = candidates.len();
+    let index = state.agent_cursor.load(Ordering::Relaxed) % count;
+    Some((candidates.swap_remove(index), count))
+}
+
+// Connection IDs of the agents that can take a request matching `filter` now, in rotation order
+fn eligible_agents(state: &AppState, filter: &AgentFilter) -> Vec<String> {
+    let mut candidates: Vec<(u64, String)> = state.connections
+        .iter()
+        .filter_map(|entry| {
+            let tunnel_id = entry.value().tunnel_id.as_deref()?;
+            if !entry.value().local_healthy || !entry.value().circuit.allows_requests() {
+                return None;
+            }
+            // An agent handling one request at a time can't take another while it streams a body
+            if entry.value().concurrency == 1 && entry.value().streaming.load(Ordering::SeqCst) {
+                return None;
+            }
+            // Agents already at --max-in-flight-per-agent are passed over for the next one
+            if state.max_in_flight_per_agent != 0
+                && entry.value().in_flight.load(Ordering::SeqCst) >= state.max_in_flight_per_agent
+            {
+                return None;
+            }
+            if !filter.matches(tunnel_id, entry.value()) {
+                return None;
+            }
+            Some((entry.value().connected_at, entry.key().clone()))
+        })
+        .collect();
+    // DashMap iteration order is arbitrary, so rotate over a stable ordering
+    candidates.sort();
+    candidates.into_iter().map(|(_, connection_id)| connection_id).collect()
+}
+
+// Compare two secrets without short-circuiting on the first mismatching byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Check the handshake token against the configured secret (if any)
+fn validate_auth_token(expected: Option<&str>, provided: Option<&str>) -> bool {
+    match expected {
+        Some(expected) => provided
+            .map(|token| constant_time_eq(expected.as_bytes(), token.as_bytes()))
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+// Check the agent's reported version against the configured minimum (if any)
+fn validate_agent_version(minimum: Option<&semver::Version>, agent_version: &str) -> Result<(), String> {
+    let Some(minimum) = minimum else {
+        return Ok(());
+    };
+    let version = semver::Version::parse(agent_version)
+        .map_err(|e| format!("Unparseable agent version '{}': {}", agent_version, e))?;
+    if version < *minimum {
+        return Err(format!(
+            "Agent version {} is older than the minimum supported version {}",
+            version, minimum
+        ));
+    }
+    Ok(())
+}
+
+// Check the tunnel against the allowlist (if any), by exact ID or UUID prefix
+fn validate_tunnel_allowed(allowlist: Option<&[String]>, tunnel_id: &str) -> bool {
+    let Some(allowlist) = allowlist else {
+        return true;
+    };
+    let uuid = tunnel_id.split('_').nth(1).unwrap_or_default();
+    allowlist.iter().any(|entry| entry == tunnel_id || uuid.starts_with(entry.as_str()))
+}
+
+// Build the allowlist from --tunnel-allowlist-file and --allowed-tunnels; blank lines
+// and # comments in the file are ignored
+fn load_tunnel_allowlist(file: Option<&FsPath>, inline: &[String]) -> std::io::Result<Option<Vec<String>>> {
+    let mut entries: Vec<String> = inline
+        .iter()
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    if let Some(path) = file {
+        let contents = std::fs::read_to_string(path)?;
+        entries.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+    if file.is_none() && entries.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(entries))
+}
+
+// Like select_agent, but when no agent is available wait up to the configured agent_wait
+// for one to complete its handshake, smoothing over agent reconnects
+async fn wait_for_agent(state: &AppState, filter: &AgentFilter) -> Option<String> {
+    let deadline = tokio::time::Instant::now() + state.agent_wait;
+    loop {
+        // Register for the notification before checking so a handshake in between isn't missed
+        let notified = state.agent_available.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        if let Some(connection_id) = select_agent(state, filter) {
+            return Some(connection_id);
+        }
+        if tokio::time::timeout_at(deadline, notified).await.is_err() {
+            return None;
+        }
+    }
+}
+
+// Counts a forward request as in flight until dropped, so draining can wait for it
+struct InFlightRequest(Arc<AppState>);
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.in_flight_requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Admit a new forward request, or None while the gateway is draining. The request is
+// counted before the check so a drain never misses one that slipped past it.
+fn begin_request(state: &Arc<AppState>) -> Option<InFlightRequest> {
+    state.in_flight_requests.fetch_add(1, Ordering::SeqCst);
+    let request = InFlightRequest(Arc::clone(state));
+    if state.draining.load(Ordering::SeqCst) {
+        return None;
+    }
+    Some(request)
+}
+
+// Apply the per-IP rate limit (if any), returning how long the client should wait when it is exceeded
+fn check_rate_limit(state: &AppState, peer: SocketAddr) -> Result<(), Duration> {
+    match &state.rate_limiter {
+        Some(limiter) => limiter.check(peer.ip()),
+        None => Ok(()),
+    }
+}
+
+// Retry-After value for a rate-limited client, in whole seconds rounded up
+fn retry_after_value(retry_after: Duration) -> HeaderValue {
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    HeaderValue::from(secs.max(1))
+}
+
+// Wait until no forward requests are in flight, giving up after `limit`
+async fn wait_for_in_flight(state: &AppState, limit: Duration) {
+    let deadline = tokio::time::Instant::now() + limit;
+    while state.in_flight_requests.load(Ordering::SeqCst) > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Wait until the given agent connections have closed, giving up at `deadline`
+// (agents that reconnect in the meantime are new connections and not waited for)
+async fn wait_for_agents_closed(state: &AppState, connection_ids: &[String], deadline: tokio::time::Instant) {
+    while connection_ids.iter().any(|id| state.connections.contains_key(id)) && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Check that the tunnel's purpose is still under its quota (if any)
+fn validate_purpose_quota(state: &AppState, tunnel_id: &str) -> Result<(), String> {
+    let Some(purpose) = tunnel_purpose(tunnel_id) else {
+        return Ok(());
+    };
+    let Some(&limit) = state.purpose_quotas.get(purpose) else {
+        return Ok(());
+    };
+    let active = state.connections
+        .iter()
+        .filter(|entry| entry.value().tunnel_id.as_deref().and_then(tunnel_purpose) == Some(purpose))
+        .count();
+    if active >= limit {
+        return Err(format!("Tunnel purpose '{}' already has its limit of {} agents connected", purpose, limit));
+    }
+    Ok(())
+}
+
+// Tell the agent why its handshake was refused, then close with a policy violation
+fn reject_handshake(state: &AppState, connection_id: &str, reason: &str) {
+    let Some(conn) = state.connections.get(connection_id) else {
+        return;
+    };
+    let error_msg = WebSocketMessage::new("error", reason.to_string());
+    if let Some(frame) = error_msg.to_frame() {
+        let _ = conn.sender.try_send(frame);
+    }
+    let _ = conn.sender.try_send(Message::Close(Some(CloseFrame {
+        code: close_code::POLICY,
+        reason: close_reason(reason).into(),
+    })));
+}
+
+// Whether a socket error came from the underlying connection (e.g. a reset) rather than
+// from the agent breaking the WebSocket protocol or its size limits
+fn is_transport_error(error: &axum::Error) -> bool {
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        if error.is::<std::io::Error>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+// Apply a health change reported by an agent after handshake; unhealthy agents are
+// skipped by select_agent until they report recovery
+fn set_local_health(state: &AppState, connection_id: &str, healthy: bool) {
+    let Some(mut conn) = state.connections.get_mut(connection_id) else {
+        return;
+    };
+    if conn.local_healthy == healthy {
+        return;
+    }
+    conn.local_healthy = healthy;
+    // Release the shard lock before waking requests that will look the agent up again
+    drop(conn);
+
+    if healthy {
+        info!("Agent {} reports its local app recovered, routing requests to it again", connection_id);
+        state.agent_available.notify_waiters();
+    } else {
+        warn!("Agent {} reports its local app is unhealthy, it will not be sent requests", connection_id);
+    }
+}
+
+// Close frame reasons are limited to 123 bytes
+fn close_reason(reason: &str) -> String {
+    let mut close_reason = reason.to_string();
+    while close_reason.len() > 123 {
+        close_reason.pop();
+    }
+    close_reason
+}
+
+// Gzip/deflate direct responses for clients that accept it. Bodies that already carry a
+// Content-Encoding are left alone, as are formats that are compressed by nature
+fn direct_compression_layer() -> CompressionLayer<impl Predicate> {
+    let predicate = DefaultPredicate::new()
+        .and(NotForContentType::const_new("application/gzip"))
+        .and(NotForContentType::const_new("application/zip"))
+        .and(NotForContentType::const_new("application/zstd"))
+        .and(NotForContentType::const_new("audio/"))
+        .and(NotForContentType::const_new("video/"))
+        .and(NotForContentType::const_new("font/woff"));
+    CompressionLayer::new().gzip(true).deflate(true).compress_when(predicate)
+}
+
+// Build the gateway's routes over `state`; serve with connect info, as handlers need the peer address
+pub fn build_app(state: Arc<AppState>) -> Router {
+    let max_body_size = state.args.max_body_size;
+    let admin_auth = middleware::from_fn_with_state(Arc::clone(&state), require_admin_token);
+    let gateway_routes = Router::new()
+        .route("/health", get(handle_health_check))
+        .route("/version", get(handle_version))
+        .route("/ready", get(handle_readiness_check))
+        .route(&state.args.ws_path, get(handle_websocket))
+        .route("/connections", get(handle_list_connections))
+        .route("/connections/summary", get(handle_connection_summary))
+        .route("/connections/:connection_id", get(handle_get_connection))
+        .route("/connections/:connection_id/disconnect", post(handle_disconnect_connection).layer(admin_auth.clone()))
+        .route("/resolve", get(handle_resolve))
+        .route("/tunnels", get(handle_list_tunnels))
+        .route("/events", get(handle_events))
+        .route("/admin/agents", get(handle_admin_agents).layer(admin_auth.clone()))
+        .route("/admin/drain", post(handle_drain).layer(admin_auth.clone()))
+        .route("/admin/reload", post(handle_reload).layer(admin_auth))
+        .route("/metrics", get(handle_metrics))
+        .route("/forward", post(handle_forward_request).layer(DefaultBodyLimit::max(max_body_size)))
+        .route(
+            "/forward/raw",
+            post(handle_forward_raw_request)
+                .put(handle_forward_raw_request)
+                .patch(handle_forward_raw_request)
+                .delete(handle_forward_raw_request),
+        );
+    let app = Router::new().route("/*path", get(handle_direct_request).layer(direct_compression_layer()));
+    // Under a prefix, the paths the gateway would otherwise claim (/health, /ws, ...) reach the tunnel
+    let app = if state.args.route_prefix.is_empty() {
+        app.merge(gateway_routes)
+    } else {
+        app.nest(&state.args.route_prefix, gateway_routes)
+    };
+    app.with_state(state)
+}
+
+// Install the tracing subscriber for --log-format; main calls this before run
+pub fn init_logging(args: &Args) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env());
+    match args.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+// Sequence 1: Gateway Startup and Initialisation
+// ----------------------------------------------
+// 1.1. Initialise logging (init_logging, called by main).
+// 1.2. Create shared state (AppState::new) to track active agent connections, and the shutdown channel.
+// 1.3. Build HTTP routes (build_app):
+//      - /health for health check,
+//      - /version for the exact build (version, git commit, build time),
+//      - /ready for readiness (503 until a handshaked agent is available),
+//      - /ws (or --ws-path) for upgrading to WebSocket (agent connections),
+//      - /connections to list active connections (and /connections/:id for one,
+//        /connections/summary for counts by purpose),
+//      - /connections/:id/disconnect to kick an agent (guarded like /admin),
+//      - /resolve to show which agent a request would be forwarded to,
+//      - /tunnels to list recently active tunnels (persisted with --state-file),
+//      - /events to stream connections coming and going as Server-Sent Events,
+//      - /admin/agents for full per-agent details (the /admin routes need
+//        --admin-token when one is set),
+//      - /admin/drain to refuse new requests ahead of a deploy,
+//      - /admin/reload to apply config file and allowlist changes without a restart,
+//      - /metrics for Prometheus scraping,
+//      - /forward, /forward/raw and catch‑all GET for request forwarding.
+//      All but the catch-all are nested under --route-prefix when one is set.
+// 1.4. Serve on the listener main bound (over TLS with --tls-cert and --tls-key) with graceful
+//      shutdown, draining in-flight requests (for up to --shutdown-grace-secs) before agents are closed.
+//      Tests call run the same way, on an ephemeral port.
+// 1.5. Return startup and serve errors to main, which decides the exit code.
+pub async fn run(args: Args, listener: tokio::net::TcpListener) -> Result<(), String> {
+    // Create shared state with DashMap
+    let state = Arc::new(AppState::new(args.clone())?);
+    let shutdown_tx = state.shutdown.clone();
+    let shutdown_tx_clone = shutdown_tx.clone();
+
+    // Load the TLS certificate and key up front, so a bad pair fails at startup rather than on first connect
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(
+            RustlsConfig::from_pem_file(cert, key)
+                .await
+                .map_err(|e| format!("Failed to load TLS certificate {} and key {}: {}", cert.display(), key.display(), e))?,
+        ),
+        _ => None,
+    };
+
+    let app = build_app(Arc::clone(&state));
+
+    let addr = listener.local_addr().map_err(|e| format!("Failed to read the listener address: {}", e))?;
+    info!("Starting gateway server on {} ({})", addr, if tls_config.is_some() { "HTTPS" } else { "HTTP" });
+    let config = state.config();
+    info!("Agent response timeout: {}s", config.request_timeout.as_secs());
+    info!("Maximum /forward body size: {} bytes", args.max_body_size);
+    info!("Maximum agent connections: {}", config.max_connections);
+    info!("Maximum agent message size: {} bytes", args.max_message_size);
+    if args.ws_compress {
+        info!("Compressing large payloads for agents that ask for it");
+    }
+    info!("Maximum agent handshake size: {} bytes", args.max_handshake_size);
+    if let Some(min_version) = &args.min_agent_version {
+        info!("Minimum agent version: {}", min_version);
+    }
+    if let Some(path) = &args.maintenance_page {
+        info!("Maintenance page: {}", path.display());
+    }
+    if args.require_known_tunnel {
+        info!("Only tunnels remembered in the state file may connect");
+    }
+    if let Some(rate) = args.rate_limit {
+        info!("Rate limit: {} requests/s per client IP (burst {})", rate, args.rate_limit_burst);
+    }
+    if args.circuit_threshold > 0 {
+        info!(
+            "Agent circuit breaker: open after {} consecutive failures, for {}s",
+            args.circuit_threshold, args.circuit_cooldown
+        );
+    }
+    let prefix = &args.route_prefix;
+    info!("Available endpoints:");
+    info!("  GET    {}/health - Health check", prefix);
+    info!("  GET    {}/version - Build version, git commit and build time", prefix);
+    info!("  GET    {}/ready - Readiness check (503 until an agent is connected)", prefix);
+    info!("  GET    {}{} - WebSocket endpoint", prefix, args.ws_path);
+    info!("  GET    {}/connections - List active connections", prefix);
+    info!("  GET    {}/connections/summary - Connection counts by tunnel purpose", prefix);
+    info!("  GET    {}/connections/:id - Inspect a single connection", prefix);
+    info!("  POST   {}/connections/:id/disconnect - Disconnect an agent", prefix);
+    info!("  GET    {}/tunnels - List recently active tunnels", prefix);
+    info!("  GET    {}/events - Stream connection lifecycle events (Server-Sent Events)", prefix);
+    info!("  GET    {}/admin/agents - Full details of every agent connection", prefix);
+    info!("  POST   {}/admin/drain - Stop accepting new requests, finishing in-flight ones", prefix);
+    info!("  POST   {}/admin/reload - Re-read --config and the tunnel allowlist", prefix);
+    info!("  GET    {}/metrics - Prometheus metrics", prefix);
+    info!("  POST   {}/forward - Forward HTTP request", prefix);
+    info!("  *      {}/forward/raw - Forward POST/PUT/PATCH/DELETE and return the raw response", prefix);
+
+    // Keep the rate limiter's table to clients that are still being throttled
+    if state.rate_limiter.is_some() {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RATE_LIMIT_PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Some(limiter) = &state.rate_limiter {
+                    limiter.prune();
+                }
+            }
+        });
+    }
+
+    // Close agents that have gone quiet for longer than --idle-timeout
+    if args.idle_timeout > 0 {
+        let state = Arc::clone(&state);
+        let idle_timeout = Duration::from_secs(args.idle_timeout);
+        info!("Idle agent timeout: {}s", args.idle_timeout);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(idle_timeout.min(IDLE_SCAN_INTERVAL));
+            loop {
+                interval.tick().await;
+                close_idle_agents(&state, idle_timeout);
+            }
+        });
+    }
+
+    // Handle shutdown signal; a second signal cuts the grace period short
+    let shutdown_grace = Duration::from_secs(args.shutdown_grace);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown signal received, draining in-flight requests (grace period {}s)...", shutdown_grace.as_secs());
+        state.draining.store(true, Ordering::SeqCst);
+        let deadline = tokio::time::Instant::now() + shutdown_grace;
+        let mut cancelled = false;
+        tokio::select! {
+            _ = wait_for_in_flight(&state, shutdown_grace) => {}
+            _ = shutdown_signal() => cancelled = true,
+        }
+        let abandoned = state.in_flight_requests.load(Ordering::SeqCst);
+        if abandoned > 0 {
+            warn!("Shutting down with {} requests still in flight", abandoned);
+        }
+
+        let connection_count = state.connections.len();
+        info!("Notifying {} connected agents...", connection_count);
+        
+        // Send close message to all connected agents
+        let mut closing = Vec::with_capacity(connection_count);
+        for entry in state.connections.iter() {
+            let close = Message::Close(Some(CloseFrame {
+                code: close_code::AWAY,
+                reason: "Gateway shutting down".into(),
+            }));
+            if let Err(e) = entry.value().sender.try_send(close) {
+                error!("Failed to send close message to agent {}: {}", entry.key(), e);
+            } else {
+                info!("Close message sent to agent {}", entry.key());
+                closing.push(entry.key().clone());
+            }
+        }
+        
+        // Give agents the rest of the grace period to acknowledge the close
+        if !cancelled {
+            tokio::select! {
+                _ = wait_for_agents_closed(&state, &closing, deadline) => {}
+                _ = shutdown_signal() => {}
+            }
+        }
+        info!("Initiating shutdown...");
+        let _ = shutdown_tx_clone.send(());
+    });
+
+    // Run the server with shutdown signal; peer addresses are needed for rate limiting
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls_config {
+        Some(tls_config) => {
+            let handle = axum_server::Handle::new();
+            let shutdown_handle = handle.clone();
+            let mut shutdown_rx = shutdown_tx.subscribe();
+            tokio::spawn(async move {
+                let _ = shutdown_rx.recv().await;
+                info!("Gateway shutdown complete");
+                shutdown_handle.graceful_shutdown(None);
+            });
+            let listener = listener.into_std().map_err(|e| format!("Failed to hand the listener to the TLS server: {}", e))?;
+            axum_server::from_tcp_rustls(listener, tls_config)
+                .handle(handle)
+                .serve(app)
+                .await
+                .map_err(|e| format!("Server error: {}", e))
+        }
+        None => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async move {
+                    let _ = shutdown_tx.subscribe().recv().await;
+                    info!("Gateway shutdown complete");
+                })
+                .await
+                .map_err(|e| format!("Server error: {}", e))
+        }
+    }
+}
+
+// Wait for Ctrl+C, or SIGTERM (as sent by systemd and Kubernetes) on unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+// Guard the /admin endpoints and agent disconnects with GATEWAY_ADMIN_TOKEN, sent as `Authorization: Bearer <token>`
+async fn require_admin_token(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let provided = request
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !validate_auth_token(state.admin_token.as_deref(), provided) {
+        warn!("Rejected unauthenticated {} {}", request.method(), request.uri().path());
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(hyper::header::WWW_AUTHENTICATE, "Bearer")],
+            Json(ApiResponse::<serde_json::Value> {
+                status: "error".to_string(),
+                message: "Missing or invalid admin token".to_string(),
+                code: None,
+                data: None,
+            }),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+// Full details of every agent connection, oldest first, for admin UIs. /connections stays the
+// lighter public listing.
+async fn handle_admin_agents(State(state): State<Arc<AppState>>) -> Json<ApiResponse<Vec<AgentDetails>>> {
+    let mut agents: Vec<AgentDetails> = state.connections
+        .iter()
+        .map(|entry| AgentDetails::new(entry.key(), entry.value()))
+        .collect();
+    agents.sort_by(|a, b| a.connected_at.cmp(&b.connected_at).then_with(|| a.connection_id.cmp(&b.connection_id)));
+
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Found {} agents", agents.len()),
+        code: None,
+        data: Some(agents),
+    })
+}
+
+// Put the gateway into drain mode ahead of a deploy. There is no way back short of a
+// restart, matching the shutdown path that sets the same flag.
+async fn handle_drain(State(state): State<Arc<AppState>>) -> Json<ApiResponse<DrainResponse>> {
+    if !state.draining.swap(true, Ordering::SeqCst) {
+        info!("Gateway is draining, new requests will be refused");
+    }
+    let in_flight_requests = state.in_flight_requests.load(Ordering::SeqCst);
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Draining, {} requests in flight", in_flight_requests),
+        code: None,
+        data: Some(DrainResponse { draining: true, in_flight_requests }),
+    })
+}
+
+// Re-read the --config file and tunnel allowlist and swap in the new settings. Requests already
+// running keep the snapshot they started with, and connected agents stay connected even if the
+// new allowlist or connection limit would refuse them. On error the old settings stay in force.
+async fn handle_reload(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ApiResponse<ReloadResponse>>) {
+    let config = match load_runtime_config(&state.args) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to reload configuration: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResponse {
+                    status: "error".to_string(),
+                    message: format!("Failed to reload configuration: {}", e),
+                    code: None,
+                    data: None,
+                }),
+            );
+        }
+    };
+    let reloaded = ReloadResponse {
+        request_timeout_secs: config.request_timeout.as_secs(),
+        max_connections: config.max_connections,
+        allowlist_entries: config.tunnel_allowlist.as_ref().map(Vec::len),
+    };
+    *state.config.write().unwrap() = Arc::new(config);
+    info!(
+        "Configuration reloaded: request timeout {}s, max connections {}, allowlist {}",
+        reloaded.request_timeout_secs,
+        reloaded.max_connections,
+        reloaded.allowlist_entries.map_or("disabled".to_string(), |entries| format!("{} entries", entries)),
+    );
+
+    (
+        StatusCode::OK,
+        Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Configuration reloaded".to_string(),
+            code: None,
+            data: Some(reloaded),
+        }),
+    )
+}
+
+// Handle health check
+async fn handle_health_check() -> Json<ApiResponse<HealthResponse>> {
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: "Gateway is running".to_string(),
+        code: None,
+        data: Some(HealthResponse {
+            version: env!("CARGO_PKG_VERSION"),
+            status: "operational",
+        }),
+    })
+}
+
+// Report which build is running, so operators can tell gateways in a fleet apart
+async fn handle_version() -> Json<ApiResponse<VersionResponse>> {
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: "Gateway build information".to_string(),
+        code: None,
+        data: Some(VersionResponse {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: env!("GATEWAY_GIT_HASH"),
+            build_timestamp: env!("GATEWAY_BUILD_TIMESTAMP").parse().unwrap_or_default(),
+        }),
+    })
+}
+
+// Handle readiness check: ready once at least one handshaked agent can take requests
+async fn handle_readiness_check(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ApiResponse<ReadyResponse>>) {
+    let ready_agents = state.connections
+        .iter()
+        .filter(|entry| entry.value().tunnel_id.is_some() && entry.value().local_healthy)
+        .count();
+
+    // Take a draining gateway out of load balancer rotation
+    if state.draining.load(Ordering::SeqCst) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: "Gateway is draining".to_string(),
+                code: None,
+                data: Some(ReadyResponse { ready_agents }),
+            }),
+        );
+    }
+
+    if ready_agents == 0 {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: "No agents available".to_string(),
+                code: None,
+                data: Some(ReadyResponse { ready_agents }),
+            }),
+        );
+    }
+    (
+        StatusCode::OK,
+        Json(ApiResponse {
+            status: "success".to_string(),
+            message: format!("{} agents ready", ready_agents),
+            code: None,
+            data: Some(ReadyResponse { ready_agents }),
+        }),
+    )
+}
+
+// Handle listing active connections
+async fn handle_list_connections(State(state): State<Arc<AppState>>) -> Json<ApiResponse<Vec<ConnectionInfo>>> {
+    let connection_list: Vec<ConnectionInfo> = state.connections
+        .iter()
+        .map(|entry| ConnectionInfo::new(entry.key(), entry.value()))
+        .collect();
+
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Found {} active connections", connection_list.len()),
+        code: None,
+        data: Some(connection_list),
+    })
+}
+
+// Count connections, grouping handshaked agents by the purpose segment of their tunnel ID
+async fn handle_connection_summary(State(state): State<Arc<AppState>>) -> Json<ApiResponse<ConnectionSummary>> {
+    let mut summary = ConnectionSummary {
+        total: 0,
+        handshaked: 0,
+        pending: 0,
+        by_purpose: BTreeMap::new(),
+        queued_requests: 0,
+    };
+    for entry in state.connections.iter() {
+        summary.total += 1;
+        summary.queued_requests += entry.value().response_handlers.len();
+        match entry.value().tunnel_id.as_deref() {
+            Some(tunnel_id) => {
+                summary.handshaked += 1;
+                let purpose = tunnel_purpose(tunnel_id).unwrap_or_default();
+                *summary.by_purpose.entry(purpose.to_string()).or_default() += 1;
+            }
+            None => summary.pending += 1,
+        }
+    }
+
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!(
+            "{} connections ({} handshaked, {} pending), {} requests queued",
+            summary.total, summary.handshaked, summary.pending, summary.queued_requests
+        ),
+        code: None,
+        data: Some(summary),
+    })
+}
+
+// Handle listing of recently active tunnels, connected or not
+async fn handle_list_tunnels(State(state): State<Arc<AppState>>) -> Json<ApiResponse<Vec<TunnelInfo>>> {
+    let known = state.known_tunnels.lock().unwrap().clone();
+    let tunnel_list: Vec<TunnelInfo> = known
+        .into_iter()
+        .map(|(tunnel_id, last_seen)| TunnelInfo {
+            connected: state.connections
+                .iter()
+                .any(|entry| entry.value().tunnel_id.as_deref() == Some(tunnel_id.as_str())),
+            tunnel_id,
+            last_seen,
+        })
+        .collect();
+
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Found {} known tunnels", tunnel_list.len()),
+        code: None,
+        data: Some(tunnel_list),
+    })
+}
+
+// Requests waiting for an agent's response: every registered response handler, streamed responses
+// included until their body ends
+fn queued_requests(state: &AppState) -> usize {
+    state.connections.iter().map(|entry| entry.value().response_handlers.len()).sum()
+}
+
+// Handle Prometheus metrics scrape
+async fn handle_metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(state.connections.len(), queued_requests(&state)),
+    )
+}
+
+// Handle looking up a single connection by ID
+async fn handle_get_connection(
+    State(state): State<Arc<AppState>>,
+    Path(connection_id): Path<String>,
+) -> (StatusCode, Json<ApiResponse<ConnectionInfo>>) {
+    match state.connections.get(&connection_id) {
+        Some(entry) => (
+            StatusCode::OK,
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "Connection found".to_string(),
+                code: None,
+                data: Some(ConnectionInfo::new(entry.key(), entry.value())),
+            }),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Connection {} not found", connection_id),
+                code: None,
+                data: None,
+            }),
+        ),
+    }
+}
+
+// Report the agent a request with these X-Tunnel-Purpose and tunnel_label inputs would be forwarded
+// to next, without forwarding anything or advancing the round-robin rotation. Errors match those
+// of a forwarded request: 404 for a purpose no agent has, 503 when no agent can take it now
+async fn handle_resolve(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<ApiResponse<ResolvedAgent>>) {
+    let filter = AgentFilter::from_request(&query, &headers);
+    let resolved = peek_agent(&state, &filter).and_then(|(connection_id, candidates)| {
+        let tunnel_id = state.connections.get(&connection_id)?.tunnel_id.clone()?;
+        Some(ResolvedAgent { connection_id, tunnel_id, candidates })
+    });
+    match resolved {
+        Some(resolved) => (
+            StatusCode::OK,
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: format!("Request would be forwarded to agent {}", resolved.connection_id),
+                code: None,
+                data: Some(resolved),
+            }),
+        ),
+        None => {
+            let (status, message, code) = match filter.rejection(&state) {
+                Some(rejection) => (rejection.status, rejection.message, rejection.code),
+                None => (StatusCode::SERVICE_UNAVAILABLE, "No agents available".to_string(), ErrorCode::NoAgents),
+            };
+            (
+                status,
+                Json(ApiResponse {
+                    status: "error".to_string(),
+                    message,
+                    code: Some(code),
+                    data: None,
+                }),
+            )
+        }
+    }
+}
+
+// Stream connection lifecycle events as Server-Sent Events until the client goes away or the
+// gateway shuts down; a subscriber too slow to keep up skips the events it missed
+async fn handle_events(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut shutdown_rx = state.shutdown.subscribe();
+    let events = futures::stream::unfold(state.events.subscribe(), |mut events_rx| async move {
+        loop {
+            match events_rx.recv().await {
+                Ok(event) => {
+                    let sse = Event::default().event(event.event).json_data(&event);
+                    return Some((sse, events_rx));
+                }
+                Err(RecvError::Lagged(skipped)) => warn!("/events subscriber fell behind, skipped {} events", skipped),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .take_until(async move {
+        let _ = shutdown_rx.recv().await;
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+// Publish a lifecycle event to /events subscribers
+fn publish_event(state: &AppState, event: &'static str, connection_id: &str, tunnel_id: Option<&str>) {
+    let _ = state.events.send(ConnectionEvent {
+        event,
+        connection_id: connection_id.to_string(),
+        tunnel_id: tunnel_id.map(str::to_string),
+        timestamp: unix_timestamp(),
+    });
+}
+
+// Handle forcibly disconnecting an agent by connection ID
+async fn handle_disconnect_connection(
+    State(state): State<Arc<AppState>>,
+    Path(connection_id): Path<String>,
+) -> (StatusCode, Json<ApiResponse<ConnectionInfo>>) {
+    match state.connections.remove(&connection_id) {
+        Some((connection_id, details)) => {
+            // The send task drains the queued close frame before it notices the sender is gone
+            let close = Message::Close(Some(CloseFrame {
+                code: close_code::NORMAL,
+                reason: "Disconnected by operator".into(),
+            }));
+            if let Err(e) = details.sender.try_send(close) {
+                warn!("Failed to send close message to agent {}: {}", connection_id, e);
+            }
+            if let Some(tunnel_id) = &details.tunnel_id {
+                remember_tunnel(&state, tunnel_id);
+            }
+            info!("Connection {} disconnected by operator", connection_id);
+            publish_event(&state, "disconnected", &connection_id, details.tunnel_id.as_deref());
+            (
+                StatusCode::OK,
+                Json(ApiResponse {
+                    status: "success".to_string(),
+                    message: format!("Connection {} disconnected", connection_id),
+                    code: None,
+                    data: Some(ConnectionInfo::new(&connection_id, &details)),
+                }),
+            )
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Connection {} not found", connection_id),
+                code: None,
+                data: None,
+            }),
+        ),
+    }
+}
+
+// Close and remove connections with no traffic from the agent for `idle_timeout`. Agents with
+// requests in flight are kept, as a slow local app is not idleness
+fn close_idle_agents(state: &AppState, idle_timeout: Duration) {
+    let now = unix_timestamp();
+    let idle: Vec<String> = state.connections
+        .iter()
+        .filter(|entry| {
+            now.saturating_sub(entry.value().last_activity.load(Ordering::Relaxed)) >= idle_timeout.as_secs()
+                && entry.value().in_flight.load(Ordering::SeqCst) == 0
+        })
+        .map(|entry| entry.key().clone())
+        .collect();
+
+    for connection_id in idle {
+        let Some((connection_id, details)) = state.connections.remove(&connection_id) else {
+            continue;
+        };
+        // The send task drains the queued close frame before it notices the sender is gone
+        let _ = details.sender.try_send(Message::Close(Some(CloseFrame {
+            code: close_code::AWAY,
+            reason: "Idle timeout".into(),
+        })));
+        if let Some(tunnel_id) = &details.tunnel_id {
+            remember_tunnel(state, tunnel_id);
+        }
+        info!("Closed connection {} after {}s without traffic", connection_id, idle_timeout.as_secs());
+        publish_event(state, "disconnected", &connection_id, details.tunnel_id.as_deref());
+    }
+}
+
+// Sequence 2: WebSocket Connection Upgrade
+// -----------------------------------------
+// 2.1. Accept an HTTP connection on /ws and upgrade it to a WebSocket.
+// 2.2. Hand over the established socket to handle_socket for the full WebSocket lifecycle.
+async fn handle_websocket(
+    State(state): State<Arc<AppState>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.max_message_size(state.max_message_size)
+        .max_frame_size(state.max_message_size)
+        .on_upgrade(|socket| handle_socket(socket, state))
+}
+
+// Sequence 3: WebSocket Communication Lifecycle (Agent Connection)
+// -----------------------------------------------------------------
+// 3.1. Generate a unique connection ID and record the timestamp.
+// 3.2. Reserve a slot under the connection limit (closing with 1013 when full), then
+//      insert the new connection into shared state with initial details.
+// 3.3. Send a "welcome" message with the connection ID to initiate the handshake.
+// 3.4. Split the WebSocket into two parallel tasks:
+//      - Sender Task: Listens for messages queued for the agent (or pong responses).
+//      - Receiver Task: Processes incoming messages (handshake, responses, ping/pong).
+// 3.5. Close connections that have not completed the handshake within the handshake timeout.
+// 3.6. On connection closure or error, remove the connection from state.
+async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
+    let connection_id = Uuid::new_v4().to_string();
+
+    // Every log line for this connection carries its IDs; tunnel_id is recorded after the handshake
+    let span = info_span!("connection", connection_id = %connection_id, tunnel_id = field::Empty);
+    serve_socket(socket, state, connection_id).instrument(span).await;
+}
+
+async fn serve_socket(mut socket: WebSocket, state: Arc<AppState>, connection_id: String) {
+    // Reserve a connection slot before touching shared state
+    let max_connections = state.config().max_connections;
+    if state.connection_count
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count < max_connections).then_some(count + 1))
+        .is_err()
+    {
+        warn!("Connection limit of {} reached, rejecting {}", max_connections, connection_id);
+        let _ = socket.send(Message::Close(Some(CloseFrame {
+            code: close_code::AGAIN,
+            reason: "Gateway at connection limit, try again later".into(),
+        }))).await;
+        // Wait briefly for the agent's close reply so the frame isn't lost to a TCP reset
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            while let Some(Ok(_)) = socket.recv().await {}
+        }).await;
+        return;
+    }
+
+    let connected_at = unix_timestamp();
+
+    let (sender, mut receiver) = mpsc::channel(state.args.agent_queue_capacity);
+    
+    // Add connection to DashMap
+    let last_activity = Arc::new(AtomicU64::new(connected_at));
+    state.connections.insert(connection_id.clone(), ConnectionDetails {
+        connected_at,
+        last_activity: Arc::clone(&last_activity),
+        tunnel_id: None,
+        local_healthy: true,
+        previous_connection_id: None,
+        labels: BTreeMap::new(),
+        agent_version: None,
+        local_url: None,
+        routes: BTreeMap::new(),
+        allowed_methods: Vec::new(),
+        allowed_paths: Vec::new(),
+        in_flight: Arc::new(AtomicUsize::new(0)),
+        request_count: Arc::new(AtomicU64::new(0)),
+        error_count: Arc::new(AtomicU64::new(0)),
+        circuit: Arc::new(CircuitBreaker::new(
+            connection_id.clone(),
+            state.args.circuit_threshold,
+            Duration::from_secs(state.args.circuit_cooldown),
+        )),
+        streaming: Arc::new(AtomicBool::new(false)),
+        concurrency: 1,
+        sender,
+        compress: false,
+        response_handlers: Vec::new(),
+    });
+    
+    info!("New WebSocket connection established: {}", connection_id);
+    publish_event(&state, "connected", &connection_id, None);
+
+    let (mut ws_sender, mut ws_receiver) = socket.split();
+
+    // Send connection ID to the client
+    let welcome = Welcome {
+        connection_id: connection_id.clone(),
+        server_version: env!("CARGO_PKG_VERSION"),
+        compression: state.args.ws_compress.then_some(COMPRESSION_GZIP),
+    };
+    let sent = match WebSocketMessage::with_json("welcome", &welcome).and_then(|message| message.to_frame()) {
+        Some(frame) => ws_sender.send(frame).await.map_err(|e| e.to_string()),
+        None => Err("it could not be encoded".to_string()),
+    };
+    if let Err(e) = sent {
+        error!("Failed to send connection ID to client: {}", e);
+        state.connections.remove(&connection_id);
+        state.connection_count.fetch_sub(1, Ordering::AcqRel);
+        publish_event(&state, "disconnected", &connection_id, None);
+        return;
+    }
+
+    // Create a channel for sending pong responses
+    let (pong_tx, mut pong_rx) = mpsc::unbounded_channel();
+    let pong_sender = pong_tx.clone();
+
+    // Handle incoming messages from other parts of the application
+    // Track the last pong so silent (half-open) agents can be detected
+    let last_pong = Arc::new(Mutex::new(Instant::now()));
+
+    let send_task = {
+        let connection_id = connection_id.clone();
+        let mut ws_sender = ws_sender;
+        let last_pong = Arc::clone(&last_pong);
+        let ping_interval = state.ping_interval;
+        let pong_timeout = state.pong_timeout;
+        tokio::spawn(async move {
+            let mut ping_timer = tokio::time::interval_at(
+                tokio::time::Instant::now() + ping_interval,
+                ping_interval,
+            );
+            loop {
+                tokio::select! {
+                    message = receiver.recv() => {
+                        // The sender lives in AppState, so None means the connection was removed
+                        let Some(message) = message else { break };
+                        if let Err(e) = ws_sender.send(message).await {
+                            error!("Failed to send message to WebSocket: {}", e);
+                            break;
+                        }
+                    }
+                    Some(pong_data) = pong_rx.recv() => {
+                        if let Err(e) = ws_sender.send(Message::Pong(pong_data)).await {
+                            error!("Failed to send pong: {}", e);
+                            break;
+                        }
+                    }
+                    _ = ping_timer.tick() => {
+                        let silent_for = last_pong.lock().unwrap().elapsed();
+                        if silent_for > pong_timeout {
+                            warn!(
+                                "No pong from {} for {}s, evicting connection",
+                                connection_id,
+                                silent_for.as_secs()
+                            );
+                            let _ = ws_sender.send(Message::Close(Some(CloseFrame {
+                                code: close_code::AWAY,
+                                reason: format!("No pong for {}s", silent_for.as_secs()).into(),
+                            }))).await;
+                            break;
+                        }
+                        if let Err(e) = ws_sender.send(Message::Ping(Vec::new())).await {
+                            error!("Failed to send ping: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+            info!("Send task ended for connection: {}", connection_id);
+        }.in_current_span())
+    };
+
+    // Handle incoming WebSocket messages
+    let recv_task = {
+        let connection_id = connection_id.clone();
+        let state = Arc::clone(&state);
+        let last_pong = Arc::clone(&last_pong);
+        tokio::spawn(async move {
+            // Highest message_seq received so far
+            let mut last_message_seq: Option<u64> = None;
+            let mut handshaked = false;
+            while let Some(msg) = ws_receiver.next().await {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(e) if is_transport_error(&e) => {
+                        info!("WebSocket connection {} lost: {}", connection_id, e);
+                        break;
+                    }
+                    Err(e) => {
+                        // Includes frames over --max-message-size, which are refused before being buffered
+                        warn!("WebSocket protocol error from {}, closing connection: {}", connection_id, e);
+                        if let Some(conn) = state.connections.get(&connection_id) {
+                            let _ = conn.sender.try_send(Message::Close(Some(CloseFrame {
+                                code: close_code::PROTOCOL,
+                                reason: close_reason(&e.to_string()).into(),
+                            })));
+                        }
+                        break;
+                    }
+                };
+                last_activity.store(unix_timestamp(), Ordering::Relaxed);
+                match msg {
+                    Message::Close(frame) => {
+                        match frame {
+                            Some(frame) => info!(
+                                "WebSocket connection {} closed by the agent with code {}: {}",
+                                connection_id, frame.code, frame.reason
+                            ),
+                            None => info!("WebSocket connection closed: {}", connection_id),
+                        }
+                        break;
+                    }
+                    // Only a handshake is expected before the agent is registered, and it is small
+                    Message::Text(text) if !handshaked && text.len() > state.args.max_handshake_size => {
+                        warn!(
+                            "Handshake of {} bytes from {} exceeds the {} byte limit, closing connection",
+                            text.len(), connection_id, state.args.max_handshake_size
+                        );
+                        if let Some(conn) = state.connections.get(&connection_id) {
+                            let _ = conn.sender.try_send(Message::Close(Some(CloseFrame {
+                                code: close_code::SIZE,
+                                reason: "Handshake too large".into(),
+                            })));
+                        }
+                        break;
+                    }
+                    Message::Text(text) => {
+                        // The handshake carries the auth token, so it is not logged verbatim
+                        if let Ok(handshake) = serde_json::from_str::<AgentHandshake>(&text) {
+                            if !validate_tunnel_id(&handshake.tunnel_id) {
+                                warn!("Invalid tunnel ID format from {}: {}", connection_id, handshake.tunnel_id);
+                                reject_handshake(&state, &connection_id, "invalid tunnel_id format, expected agent_{uuid}_{purpose}");
+                                break;
+                            }
+                            if !validate_auth_token(state.auth_token.as_deref(), handshake.auth_token.as_deref()) {
+                                warn!("Invalid auth token from {} for tunnel ID: {}", connection_id, handshake.tunnel_id);
+                                reject_handshake(&state, &connection_id, "Invalid auth token");
+                                break;
+                            }
+                            if !validate_tunnel_allowed(state.config().tunnel_allowlist.as_deref(), &handshake.tunnel_id) {
+                                warn!("Tunnel ID {} from {} is not on the allowlist", handshake.tunnel_id, connection_id);
+                                reject_handshake(&state, &connection_id, "Tunnel ID is not on the allowlist");
+                                break;
+                            }
+                            if state.args.require_known_tunnel && !is_known_tunnel(&state, &handshake.tunnel_id) {
+                                warn!("Tunnel ID {} from {} is not in the state file", handshake.tunnel_id, connection_id);
+                                reject_handshake(&state, &connection_id, "Tunnel ID is not a known tunnel");
+                                break;
+                            }
+                            if let Err(reason) = validate_purpose_quota(&state, &handshake.tunnel_id) {
+                                warn!("Rejecting agent {}: {}", connection_id, reason);
+                                reject_handshake(&state, &connection_id, &reason);
+                                break;
+                            }
+                            if let Err(reason) = validate_agent_version(state.min_agent_version.as_ref(), &handshake.agent_version) {
+                                warn!("Rejecting agent {}: {}", connection_id, reason);
+                                reject_handshake(&state, &connection_id, &reason);
+                                break;
+                            }
+                            info!(
+                                "Valid handshake from {} with tunnel ID: {} (agent version {})",
+                                connection_id, handshake.tunnel_id, handshake.agent_version
+                            );
+                            
+                            Span::current().record("tunnel_id", handshake.tunnel_id.as_str());
+                            remember_tunnel(&state, &handshake.tunnel_id);
+
+                            let local_healthy = handshake.local_healthy.unwrap_or(true);
+                            if !local_healthy {
+                                warn!("Agent {} reports its local app is unhealthy, it will not be sent requests", connection_id);
+                            }
+                            if let Some(previous) = &handshake.previous_connection_id {
+                                info!("Agent {} is reconnecting, previously connection {}", connection_id, previous);
+                            }
+
+                            // Update connection with tunnel ID using proper mutable access
+                            publish_event(&state, "handshaked", &connection_id, Some(&handshake.tunnel_id));
+                            if let Some(mut conn) = state.connections.get_mut(&connection_id) {
+                                conn.tunnel_id = Some(handshake.tunnel_id);
+                                conn.local_healthy = local_healthy;
+                                conn.previous_connection_id = handshake.previous_connection_id;
+                                conn.labels = handshake.labels;
+                                conn.agent_version = Some(handshake.agent_version);
+                                conn.local_url = handshake.local_url;
+                                conn.routes = handshake.routes;
+                                conn.concurrency = handshake.concurrency.unwrap_or(1).max(1);
+                                conn.allowed_methods = handshake.allowed_methods.iter().map(|method| method.to_ascii_uppercase()).collect();
+                                conn.allowed_paths = handshake.allowed_paths;
+                                conn.compress = state.args.ws_compress && handshake.compression.as_deref() == Some(COMPRESSION_GZIP);
+                            }
+                            handshaked = true;
+                            state.agent_available.notify_waiters();
+                        } else {
+                            let parsed = serde_json::from_str::<WebSocketMessage>(&text)
+                                .map_err(|e| e.to_string())
+                                .and_then(|msg| msg.decompress(state.max_message_size));
+                            if let Ok(msg) = &parsed {
+                                check_message_seq(&connection_id, msg.message_seq, &mut last_message_seq);
+                            }
+                            match parsed {
+                                // Application-level keepalive for agents behind proxies that drop
+                                // control frames: counts as a pong and is acknowledged in kind
+                                Ok(msg) if msg.message_type == "heartbeat" => {
+                                    *last_pong.lock().unwrap() = Instant::now();
+                                    if let Some(conn) = state.connections.get(&connection_id) {
+                                        if let Some(frame) = WebSocketMessage::new("heartbeat_ack", String::new()).to_frame() {
+                                            let _ = conn.sender.try_send(frame);
+                                        }
+                                    }
+                                }
+                                Ok(msg) if msg.message_type == "health" => {
+                                    match serde_json::from_str::<HealthReport>(&msg.payload) {
+                                        Ok(report) => set_local_health(&state, &connection_id, report.healthy),
+                                        Err(e) => warn!("Invalid health report from {}: {}", connection_id, e),
+                                    }
+                                }
+                                Ok(msg) => {
+                                    // Body chunks and tunneled frames are too large and too frequent to log
+                                    if !matches!(msg.message_type.as_str(), "response_chunk" | "ws_frame") {
+                                        info!("Received message from {}: {}", connection_id, text);
+                                    }
+                                    route_agent_message(&state, &connection_id, msg);
+                                }
+                                Err(e) => info!("Received message from {} ({}): {}", connection_id, e, text),
+                            }
+                        }
+                    }
+                    Message::Ping(data) => {
+                        if let Err(e) = pong_sender.send(data) {
+                            error!("Failed to queue pong: {}", e);
+                            if let Some(conn) = state.connections.get(&connection_id) {
+                                let _ = conn.sender.try_send(Message::Close(Some(CloseFrame {
+                                    code: close_code::ERROR,
+                                    reason: "Internal error".into(),
+                                })));
+                            }
+                            break;
+                        }
+                    }
+                    Message::Pong(_) => {
+                        // Pong received, connection is alive
+                        *last_pong.lock().unwrap() = Instant::now();
+                    }
+                    _ => {}
+                }
+            }
+            info!("Receive task ended for connection: {}", connection_id);
+        }.in_current_span())
+    };
+
+    // Wait for either task to finish. If the send side gives up (e.g. pong deadline
+    // missed) the receiver may be stuck on a half-open socket, so abort it. The send
+    // side is left running when the receiver ends so queued close frames still go out.
+    let mut send_task = send_task;
+    let mut recv_task = recv_task;
+
+    // Resolves only if the agent has not completed its handshake in time
+    let handshake_deadline = {
+        let state = Arc::clone(&state);
+        let connection_id = connection_id.clone();
+        async move {
+            tokio::time::sleep(state.handshake_timeout).await;
+            let handshaked = state.connections
+                .get(&connection_id)
+                .map(|conn| conn.tunnel_id.is_some())
+                .unwrap_or(true);
+            if handshaked {
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = &mut send_task => {
+            info!("Send task finished first");
+            recv_task.abort();
+        }
+        _ = &mut recv_task => {
+            info!("Receive task finished first");
+        }
+        _ = handshake_deadline => {
+            warn!(
+                "No valid handshake from {} within {}s, closing connection",
+                connection_id,
+                state.handshake_timeout.as_secs()
+            );
+            if let Some(conn) = state.connections.get(&connection_id) {
+                let _ = conn.sender.try_send(Message::Close(Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "Handshake timeout".into(),
+                })));
+            }
+            recv_task.abort();
+        }
+    }
+
+    // Clean up connection, closing any client WebSockets tunneled through it
+    // (an operator disconnect has already removed it and published its event)
+    if let Some((_, details)) = state.connections.remove(&connection_id) {
+        if let Some(tunnel_id) = &details.tunnel_id {
+            remember_tunnel(&state, tunnel_id);
+        }
+        publish_event(&state, "disconnected", &connection_id, details.tunnel_id.as_deref());
+    }
+    state.tunnel_streams.retain(|_, stream| stream.connection_id != connection_id);
+    state.connection_count.fetch_sub(1, Ordering::AcqRel);
+    info!("Connection cleaned up: {}", connection_id);
+}
+
+// Compare an agent message's number with the highest seen on its connection, warning about gaps
+// (dropped messages) and numbers at or below it (reordered or repeated messages). Diagnostic only:
+// the message is handled either way
+fn check_message_seq(connection_id: &str, seq: Option<u64>, last_seen: &mut Option<u64>) {
+    let Some(seq) = seq else {
+        return;
+    };
+    match *last_seen {
+        Some(last) if seq <= last => {
+            warn!("Message {} from {} arrived after message {}, out of order or repeated", seq, connection_id, last);
+            return;
+        }
+        Some(last) if seq > last + 1 => {
+            warn!("{} messages from {} missing before message {}", seq - last - 1, connection_id, seq);
+        }
+        None if seq > 0 => warn!("{} messages from {} missing before message {}", seq, connection_id, seq),
+        _ => {}
+    }
+    *last_seen = Some(seq);
+}
+
+// Find the handler waiting for an agent reply: the one whose request_id the reply carries, or
+// for older agents, which answer in order, the oldest. `update` sees it under the map guard and
+// returns whether the request is now complete, removing the handler. The sender is cloned so no
+// guard is held across awaits
+fn find_response_handler(
+    state: &AppState,
+    connection_id: &str,
+    request_id: Option<&str>,
+    update: impl FnOnce(&mut ResponseHandler) -> bool,
+) -> Option<mpsc::Sender<AgentReply>> {
+    let mut conn = state.connections.get_mut(connection_id)?;
+    let index = match request_id {
+        Some(request_id) => conn.response_handlers.iter().position(|handler| handler.request_id == request_id)?,
+        None if conn.response_handlers.is_empty() => return None,
+        None => 0,
+    };
+    let sender = conn.response_handlers[index].sender.clone();
+    if update(&mut conn.response_handlers[index]) {
+        conn.response_handlers.remove(index);
+    }
+    Some(sender)
+}
+
+// Hand a reply to the request waiting for it without waiting on a full channel, which would stall
+// the agent's receive loop and every other request on the connection behind it. A request whose
+// client isn't keeping up is failed instead: its handler is dropped, which ends its response, and the
+// agent is told to stop. Returns false if the request was no longer waiting
+fn deliver_reply(state: &AppState, connection_id: &str, handler: mpsc::Sender<AgentReply>, reply: AgentReply) -> bool {
+    match handler.try_send(reply) {
+        Ok(()) => true,
+        Err(mpsc::error::TrySendError::Closed(_)) => false,
+        Err(mpsc::error::TrySendError::Full(_)) => {
+            let Some(mut conn) = state.connections.get_mut(connection_id) else {
+                return true;
+            };
+            let Some(index) = conn.response_handlers.iter().position(|waiting| waiting.sender.same_channel(&handler)) else {
+                return true;
+            };
+            let request_id = conn.response_handlers.remove(index).request_id;
+            warn!(
+                "Failing request {} on agent {}: its client is not keeping up with the response",
+                request_id, connection_id
+            );
+            if let Some(frame) = WebSocketMessage::new("cancel", request_id).to_frame() {
+                let _ = conn.sender.try_send(frame);
+            }
+            true
+        }
+    }
+}
+
+// Deliver an agent message to the forward handler waiting for it.
+// Streamed bodies arrive as a "response" head with data.streamed = true, followed by
+// base64 "response_chunk" messages numbered from 0 and a "response_end" carrying the
+// chunk count. Any gap, bad chunk, or "error" mid-stream abandons the stream, which
+// drops the handler so the client sees a truncated (failed) body. Agents handling several
+// requests at once interleave the messages of their replies, told apart by request_id.
+fn route_agent_message(state: &AppState, connection_id: &str, msg: WebSocketMessage) {
+    let request_id = msg.request_id.as_deref();
+    match msg.message_type.as_str() {
+        "response" => {
+            info!("Received response from agent {}: {}", connection_id, msg.payload);
+            let Ok(response) = serde_json::from_str::<serde_json::Value>(&msg.payload) else {
+                return;
+            };
+            let streamed = response["data"]["streamed"].as_bool().unwrap_or(false);
+            let handler = find_response_handler(state, connection_id, request_id, |handler| {
+                if streamed {
+                    handler.next_chunk = Some(0);
+                }
+                !streamed
+            });
+            let delivered = match handler {
+                Some(handler) => deliver_reply(state, connection_id, handler, AgentReply::Response(response)),
+                None => false,
+            };
+            // Usually the request timed out or its client went away before the agent answered, so
+            // frequent orphans suggest --request-timeout is too tight for this agent
+            if !delivered {
+                match request_id {
+                    Some(request_id) => warn!(
+                        "Dropped response from agent {} for unknown request ID {} (timed out or cancelled)",
+                        connection_id, request_id
+                    ),
+                    None => warn!(
+                        "Dropped response from agent {}: no request is waiting for it (timed out or cancelled)",
+                        connection_id
+                    ),
+                }
+                state.metrics.orphaned_responses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        "response_chunk" => {
+            let chunk = BASE64.decode(msg.payload.as_bytes());
+            let mut problem = None;
+            let handler = find_response_handler(state, connection_id, request_id, |handler| {
+                match (handler.next_chunk, &chunk) {
+                    (None, _) => {
+                        problem = Some("no streamed response in progress".to_string());
+                        false
+                    }
+                    (Some(expected), Ok(_)) if msg.sequence == Some(expected) => {
+                        handler.next_chunk = Some(expected + 1);
+                        false
+                    }
+                    (Some(expected), Ok(_)) => {
+                        problem = Some(format!("out of order, expected {}, got {:?}", expected, msg.sequence));
+                        true
+                    }
+                    (Some(_), Err(e)) => {
+                        problem = Some(format!("invalid chunk: {}", e));
+                        true
+                    }
+                }
+            });
+            if let Some(problem) = problem {
+                warn!("Discarding response chunk from {}: {}", connection_id, problem);
+                return;
+            }
+            // No handler: the client went away, so the rest of the body goes nowhere
+            let (Some(handler), Ok(chunk)) = (handler, chunk) else {
+                return;
+            };
+            if !deliver_reply(state, connection_id, handler, AgentReply::Chunk(Bytes::from(chunk))) {
+                find_response_handler(state, connection_id, request_id, |_| true);
+            }
+        }
+        "response_end" => {
+            let mut expected = None;
+            let handler = find_response_handler(state, connection_id, request_id, |handler| {
+                expected = handler.next_chunk;
+                expected.is_some()
+            });
+            let Some(expected) = expected else {
+                return;
+            };
+            if msg.sequence != Some(expected) {
+                warn!(
+                    "Streamed response from {} ended after {} chunks, agent reported {:?}",
+                    connection_id, expected, msg.sequence
+                );
+                return;
+            }
+            // The payload is empty, or the trailers as [name, value] pairs
+            let trailers = trailer_map(&serde_json::from_str(&msg.payload).unwrap_or_default());
+            if let Some(handler) = handler {
+                deliver_reply(state, connection_id, handler, AgentReply::End(trailers));
+            }
+        }
+        "ws_frame" => {
+            let Ok(frame) = serde_json::from_str::<TunnelFrame>(&msg.payload) else {
+                warn!("Invalid ws_frame payload from {}", connection_id);
+                return;
+            };
+            let message = if frame.binary {
+                match BASE64.decode(frame.data.as_bytes()) {
+                    Ok(data) => Message::Binary(data),
+                    Err(e) => {
+                        warn!("Invalid binary ws_frame from {}: {}", connection_id, e);
+                        return;
+                    }
+                }
+            } else {
+                Message::Text(frame.data)
+            };
+            if let Some(stream) = state.tunnel_streams.get(&frame.stream_id) {
+                let _ = stream.client.send(message);
+            }
+        }
+        "ws_close" => {
+            let Ok(close) = serde_json::from_str::<TunnelClose>(&msg.payload) else {
+                warn!("Invalid ws_close payload from {}", connection_id);
+                return;
+            };
+            info!("Agent {} closed tunneled WebSocket {}: {}", connection_id, close.stream_id, close.reason);
+            if let Some((_, stream)) = state.tunnel_streams.remove(&close.stream_id) {
+                let _ = stream.client.send(Message::Close(Some(CloseFrame {
+                    code: close.code.unwrap_or(close_code::NORMAL),
+                    reason: close.reason.into(),
+                })));
+            }
+        }
+        "error" => {
+            let mut streaming = false;
+            let handler = find_response_handler(state, connection_id, request_id, |handler| {
+                streaming = handler.next_chunk.is_some();
+                true
+            });
+            if streaming {
+                warn!("Agent {} aborted streamed response: {}", connection_id, msg.payload);
+                return;
+            }
+            if let Some(handler) = handler {
+                deliver_reply(state, connection_id, handler, AgentReply::Error(msg.payload));
+            }
+        }
+        _ => {}
+    }
+}
+
+// Read the remaining chunks of a streamed body and its trailers, waiting at most `timeout` for each
+async fn collect_streamed_body(
+    response_rx: &mut mpsc::Receiver<AgentReply>,
+    timeout: Duration,
+) -> Result<(Vec<u8>, HeaderMap), String> {
+    let mut body = Vec::new();
+    loop {
+        match tokio::time::timeout(timeout, response_rx.recv()).await {
+            Ok(Some(AgentReply::Chunk(chunk))) => body.extend_from_slice(&chunk),
+            Ok(Some(AgentReply::End(trailers))) => return Ok((body, trailers)),
+            Ok(Some(AgentReply::Response(_))) => return Err("Unexpected response while streaming body".to_string()),
+            Ok(Some(AgentReply::Error(message))) => return Err(message),
+            Ok(None) => return Err("Agent aborted streamed response".to_string()),
+            Err(_) => return Err(timeout_message(timeout)),
+        }
+    }
+}
+
+// Relay a streamed body to the client as it arrives, followed by its trailers; ends with an error
+// if the agent aborts. The agent slot lives as long as the body, so a client that goes away
+// mid-stream (e.g. closing an event stream) cancels the request on the agent
+fn streamed_body(response_rx: mpsc::Receiver<AgentReply>, mut agent_slot: AgentSlot) -> Body {
+    agent_slot.mark_streaming();
+    let stream = futures::stream::unfold(Some((response_rx, agent_slot)), |relay| async move {
+        let (mut response_rx, mut agent_slot) = relay?;
+        match response_rx.recv().await {
+            Some(AgentReply::Chunk(chunk)) => Some((Ok(Frame::data(chunk)), Some((response_rx, agent_slot)))),
+            Some(AgentReply::End(trailers)) => {
+                agent_slot.mark_answered();
+                (!trailers.is_empty()).then(|| (Ok(Frame::trailers(trailers)), None))
+            }
+            Some(AgentReply::Response(_)) | Some(AgentReply::Error(_)) | None => {
+                agent_slot.mark_answered();
+                agent_slot.record_error();
+                Some((Err(std::io::Error::other("agent aborted streamed response")), None))
+            }
+        }
+    });
+    Body::new(StreamBody::new(stream))
+}
+
+// A buffered body followed by trailers, which HTTP/1.1 clients only get if they sent TE: trailers
+fn body_with_trailers(body: Bytes, trailers: HeaderMap) -> Body {
+    let frames = [Frame::data(body), Frame::trailers(trailers)].map(Ok::<_, std::convert::Infallible>);
+    Body::new(StreamBody::new(futures::stream::iter(frames)))
+}
+
+// Trailers as the [name, value] pairs of the tunnel protocol
+fn trailer_pairs(trailers: &HeaderMap) -> Vec<(String, String)> {
+    trailers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+// Trailers from the tunnel protocol's [name, value] pairs, skipping any that aren't valid fields
+fn trailer_map(pairs: &serde_json::Value) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    for pair in pairs.as_array().into_iter().flatten() {
+        let (Some(name), Some(value)) = (pair[0].as_str(), pair[1].as_str()) else {
+            continue;
+        };
+        if let (Ok(name), Ok(value)) = (hyper::header::HeaderName::try_from(name), HeaderValue::from_str(value)) {
+            trailers.append(name, value);
+        }
+    }
+    trailers
+}
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_REAL_IP: &str = "x-real-ip";
+
+// Headers that describe a single hop (or that the agent recomputes): the default --strip-header list,
+// and never copied from agent responses
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+];
+
+// Headers describing the client connection's framing, never forwarded whatever --strip-header says
+const FRAMING_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+// Collect the client's headers for forwarding, dropping framing headers, the --strip-header list,
+// anything listed in the Connection header, and values that aren't valid UTF-8
+fn forwardable_headers(headers: &HeaderMap, strip_headers: &[String]) -> Vec<(String, String)> {
+    let connection_listed: Vec<String> = headers
+        .get_all(hyper::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            !FRAMING_HEADERS.contains(&name)
+                && !strip_headers.iter().any(|stripped| stripped == name)
+                && !connection_listed.iter().any(|listed| listed == name)
+        })
+        .filter_map(|(name, value)| {
+            value.to_str().ok().map(|value| (name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+// Identify the client to the local app: X-Forwarded-For gains the peer address after any
+// proxies the client came through, and X-Real-IP is always the peer itself (never trusted
+// from the client)
+fn add_client_ip_headers(headers: &mut HeaderMap, peer: SocketAddr) {
+    let ip = peer.ip().to_string();
+    let mut forwarded_for: Vec<&str> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    forwarded_for.push(&ip);
+    let forwarded_for = forwarded_for.join(", ");
+
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&ip) {
+        headers.insert(X_REAL_IP, value);
+    }
+}
+
+// Sequence 4: Forward HTTP Request via Agent (POST /forward)
+// -----------------------------------------------------------
+// 4.1. Receive a POST HTTP request to forward (429 over the client's rate limit, 503 while draining).
+// 4.2. Create a one-shot response channel to receive the agent's reply.
+// 4.3. Select an available agent that has completed the handshake (has a valid tunnel_id) and
+//      carries every label requested with `tunnel_label=KEY:VALUE` query parameters.
+// 4.4. Register the response channel in the agent connection's response_handlers, under the request_id.
+// 4.5. Construct and send the forward message (containing method, path, body and the client's
+//      end-to-end headers plus X-Forwarded-For and X-Real-IP) over WebSocket.
+// 4.6. Wait for the agent's response with the configured timeout and return it to the HTTP client.
+// 4.7. Errors carry an ErrorCode alongside the message for programmatic clients.
+async fn handle_forward_request(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(query): Query<Vec<(String, String)>>,
+    mut headers: HeaderMap,
+    body: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Response {
+    if let Err(retry_after) = check_rate_limit(&state, peer) {
+        warn!("Rate limited /forward request from {}", peer.ip());
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(hyper::header::RETRY_AFTER, retry_after_value(retry_after))],
+            Json(ApiResponse::<serde_json::Value> {
+                status: "error".to_string(),
+                message: "Too many requests".to_string(),
+                code: Some(ErrorCode::RateLimited),
+                data: None,
+            }),
+        )
+            .into_response();
+    }
+    let Some(_in_flight) = begin_request(&state) else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ApiResponse::<serde_json::Value> {
+                status: "error".to_string(),
+                message: "Gateway is draining".to_string(),
+                code: Some(ErrorCode::Draining),
+                data: None,
+            }),
+        )
+            .into_response();
+    };
+    add_client_ip_headers(&mut headers, peer);
+    let started = Instant::now();
+    let filter = AgentFilter::from_request(&query, &headers).for_request("POST", "/");
+    let mut served_by = None;
+    let response = forward_request(Arc::clone(&state), &filter, headers, body, &mut served_by).await;
+    let tunnel_id = served_by.as_ref().and_then(|served_by| served_by.tunnel_id.as_deref());
+    audit::record(state.audit.as_ref(), "POST", "/", tunnel_id, response.status(), started);
+    with_served_by(response, served_by.as_ref())
+}
+
+// Body of handle_forward_request; records the selected agent in `served_by`
+async fn forward_request(
+    state: Arc<AppState>,
+    filter: &AgentFilter,
+    headers: HeaderMap,
+    body: Result<Json<serde_json::Value>, JsonRejection>,
+    served_by: &mut Option<ServedBy>,
+) -> Response {
+    // Malformed or non-JSON bodies get a clean ApiResponse instead of axum's plain-text rejection
+    let body = match body {
+        Ok(Json(body)) => body,
+        Err(rejection) => {
+            let status = match rejection.status() {
+                StatusCode::PAYLOAD_TOO_LARGE => StatusCode::PAYLOAD_TOO_LARGE,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            warn!("Rejected /forward request body: {}", rejection.body_text());
+            return (
+                status,
+                Json(ApiResponse::<serde_json::Value> {
+                    status: "error".to_string(),
+                    message: format!("Invalid request body: {}", rejection.body_text()),
+                    code: Some(ErrorCode::InvalidRequest),
+                    data: None,
+                }),
+            )
+                .into_response();
+        }
+    };
+    // One timeout for the whole request, even if the config is reloaded meanwhile
+    let request_timeout = state.config().request_timeout;
+    let (response_tx, mut response_rx) = mpsc::channel(RESPONSE_CHANNEL_CAPACITY);
+    let forwarded_headers = forwardable_headers(&headers, &state.args.strip_headers);
+    
+    // Pick the next agent in rotation
+    let mut agent_slot = None;
+    let mut send_result = Ok(());
+
+    if let Some(mut entry) = wait_for_agent(&state, filter).await.and_then(|id| state.connections.get_mut(&id)) {
+        let slot = AgentSlot::acquire(&state, entry.key(), entry.value());
+        *served_by = Some(ServedBy::new(entry.key(), entry.value()));
+        state.metrics.forwarded_requests.fetch_add(1, Ordering::Relaxed);
+        let request = ForwardedRequest {
+            method: "POST".to_string(),
+            path: "/".to_string(),
+            body: body.to_string(),
+            binary: false,
+            headers: forwarded_headers.clone(),
+            chunked: false,
+            trailers: Vec::new(),
+            request_id: slot.request_id.clone(),
+        };
+
+        entry.value_mut().response_handlers.push(ResponseHandler::new(&slot.request_id, response_tx.clone()));
+        send_result = send_forwarded_request(entry.value(), &request);
+        agent_slot = Some(slot);
+    }
+    // Only the agent's connection holds the sender now, so losing it closes the channel
+    drop(response_tx);
+
+    let Some(mut agent_slot) = agent_slot else {
+        state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+        if let Some(rejection) = filter.rejection(&state) {
+            return rejection.into_api_response();
+        }
+        if filter.saturated(&state) {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ApiResponse::<serde_json::Value> {
+                    status: "error".to_string(),
+                    message: "All agents are at their in-flight request limit".to_string(),
+                    code: Some(ErrorCode::AgentsSaturated),
+                    data: None,
+                }),
+            )
+                .into_response();
+        }
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ApiResponse::<serde_json::Value> {
+                status: "error".to_string(),
+                message: "No agents available".to_string(),
+                code: Some(ErrorCode::NoAgents),
+                data: None,
+            }),
+        )
+            .into_response();
+    };
+
+    // Handle send result
+    match send_result {
+        Ok(_) => {
+            // Wait for response with timeout
+            let reply = tokio::time::timeout(request_timeout, response_rx.recv()).await;
+            if let Ok(Some(_)) = reply {
+                agent_slot.mark_answered();
+            }
+            match reply {
+                Ok(Some(AgentReply::Response(mut response))) => {
+                    info!("Received and forwarding agent response to client");
+                    // Reassemble streamed bodies so the client gets the usual single JSON document
+                    if response["data"]["streamed"].as_bool().unwrap_or(false) {
+                        agent_slot.mark_streaming();
+                        match collect_streamed_body(&mut response_rx, request_timeout).await {
+                            Ok((body, trailers)) => {
+                                agent_slot.mark_answered();
+                                response["data"]["body"] = serde_json::Value::String(String::from_utf8_lossy(&body).into_owned());
+                                if !trailers.is_empty() {
+                                    response["data"]["trailers"] = serde_json::json!(trailer_pairs(&trailers));
+                                }
+                                if let Some(data) = response["data"].as_object_mut() {
+                                    data.remove("streamed");
+                                }
+                            }
+                            Err(message) => {
+                                state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+                                agent_slot.record_error();
+                                error!("Failed to reassemble streamed response: {}", message);
+                                return (
+                                    StatusCode::BAD_GATEWAY,
+                                    Json(ApiResponse::<serde_json::Value> {
+                                        status: "error".to_string(),
+                                        message,
+                                        code: Some(ErrorCode::AgentError),
+                                        data: None,
+                                    }),
+                                )
+                                    .into_response();
+                            }
+                        }
+                    }
+                    // The response here is already parsed by the WebSocket handler
+                    Json(ApiResponse {
+                        status: "success".to_string(),
+                        message: "Request processed by agent".to_string(),
+                        code: None,
+                        data: Some(response),
+                    }).into_response()
+                }
+                Ok(Some(AgentReply::Error(message))) => {
+                    state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+                    agent_slot.record_error();
+                    error!("Agent failed to handle request: {}", message);
+                    (
+                        StatusCode::BAD_GATEWAY,
+                        Json(ApiResponse::<serde_json::Value> {
+                            status: "error".to_string(),
+                            message,
+                            code: Some(ErrorCode::AgentError),
+                            data: None,
+                        }),
+                    )
+                        .into_response()
+                }
+                Ok(Some(_)) | Ok(None) => {
+                    state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+                    agent_slot.record_error();
+                    error!("Response channel closed without response");
+                    (
+                        StatusCode::BAD_GATEWAY,
+                        Json(ApiResponse::<serde_json::Value> {
+                            status: "error".to_string(),
+                            message: "Agent connection lost".to_string(),
+                            code: Some(ErrorCode::AgentLost),
+                            data: None,
+                        }),
+                    )
+                        .into_response()
+                }
+                Err(_) => {
+                    state.metrics.request_timeouts.fetch_add(1, Ordering::Relaxed);
+                    agent_slot.record_error();
+                    let message = timeout_message(request_timeout);
+                    error!("{}", message);
+                    (
+                        StatusCode::GATEWAY_TIMEOUT,
+                        Json(ApiResponse::<serde_json::Value> {
+                            status: "error".to_string(),
+                            message,
+                            code: Some(ErrorCode::AgentTimeout),
+                            data: None,
+                        }),
+                    )
+                        .into_response()
+                }
+            }
+        }
+        Err(e) => {
+            record_send_failure(&state, &mut agent_slot, &e);
+            (
+                e.status(),
+                Json(ApiResponse::<serde_json::Value> {
+                    status: "error".to_string(),
+                    message: format!("Failed to send request to agent: {}", e),
+                    code: Some(e.code()),
+                    data: None,
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+// Why a request could not be queued on an agent's connection
+enum SendFailure {
+    // The agent isn't reading its socket fast enough and --agent-queue-capacity messages are waiting
+    QueueFull,
+    // The connection is closing, or the request could not be encoded
+    Failed(String),
+}
+
+impl SendFailure {
+    // A full queue is transient, so the client is told to retry (503) rather than that the gateway failed
+    fn status(&self) -> StatusCode {
+        match self {
+            SendFailure::QueueFull => StatusCode::SERVICE_UNAVAILABLE,
+            SendFailure::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> ErrorCode {
+        match self {
+            SendFailure::QueueFull => ErrorCode::AgentQueueFull,
+            SendFailure::Failed(_) => ErrorCode::SendFailed,
+        }
+    }
+}
+
+impl std::fmt::Display for SendFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendFailure::QueueFull => write!(f, "agent is not keeping up, its outgoing queue is full"),
+            SendFailure::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// Serialize a request and queue it on the agent's connection without waiting, so a request for an
+// agent that can't keep up is shed instead of adding to its backlog. Encoding only fails on a bug,
+// but that is reported like a failed send (500) rather than panicking the handler
+fn send_forwarded_request(agent: &ConnectionDetails, request: &ForwardedRequest) -> Result<(), SendFailure> {
+    let message = serde_json::to_string(request)
+        .map(|payload| WebSocketMessage::new("request", payload))
+        .map(|message| if agent.compress { message.compress() } else { message })
+        .and_then(|message| serde_json::to_string(&message))
+        .map_err(|e| SendFailure::Failed(format!("could not encode request: {}", e)))?;
+    agent.sender.try_send(Message::Text(message)).map_err(|e| match e {
+        mpsc::error::TrySendError::Full(_) => SendFailure::QueueFull,
+        mpsc::error::TrySendError::Closed(_) => SendFailure::Failed("channel closed".to_string()),
+    })
+}
+
+// Count a request that never reached its agent. A shed request isn't the agent's failure, so it
+// doesn't count towards its circuit breaker
+fn record_send_failure(state: &AppState, agent_slot: &mut AgentSlot, failure: &SendFailure) {
+    state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+    match failure {
+        SendFailure::QueueFull => {
+            state.metrics.shed_requests.fetch_add(1, Ordering::Relaxed);
+            warn!("Shedding request to agent {}: {}", agent_slot.connection_id, failure);
+        }
+        SendFailure::Failed(_) => {
+            agent_slot.record_error();
+            error!("Failed to send request to agent: {}", failure);
+        }
+    }
+}
+
+// Methods safe to send again when the agent disconnected without replying, as the local app may
+// already have handled the first attempt
+const IDEMPOTENT_METHODS: &[&str] = &["GET", "HEAD", "OPTIONS", "PUT", "DELETE"];
+// Times a request is replayed on another agent before "Agent connection lost" is returned
+const MAX_REPLAYS: usize = 2;
+
+// Wait until `deadline` for the agent's first reply to `request`. If the agent disconnects first
+// and the request is idempotent, it is replayed on another available agent (with a fresh
+// request_id), swapping in that agent's slot, response channel and `served_by`; the lost agent
+// is charged with the failure as before
+async fn await_first_reply(
+    state: &Arc<AppState>,
+    filter: &AgentFilter,
+    request: &mut ForwardedRequest,
+    agent_slot: &mut AgentSlot,
+    response_rx: &mut mpsc::Receiver<AgentReply>,
+    served_by: &mut Option<ServedBy>,
+    deadline: tokio::time::Instant,
+) -> Result<Option<AgentReply>, tokio::time::error::Elapsed> {
+    let mut replays = 0;
+    loop {
+        let reply = tokio::time::timeout_at(deadline, response_rx.recv()).await;
+        // A closed channel while the connection is still registered means the handler was dropped
+        // over a protocol error, not that the agent went away
+        let agent_lost = matches!(reply, Ok(None))
+            && served_by.as_ref().is_some_and(|served_by| !state.connections.contains_key(&served_by.connection_id));
+        if !agent_lost || replays == MAX_REPLAYS || !IDEMPOTENT_METHODS.contains(&request.method.as_str()) {
+            return reply;
+        }
+        let Some(mut entry) = select_agent(state, filter).and_then(|id| state.connections.get_mut(&id)) else {
+            return reply;
+        };
+        replays += 1;
+        warn!(
+            "Agent disconnected before answering {} {}, replaying it on agent {}",
+            request.method,
+            request.path,
+            entry.key()
+        );
+        state.metrics.replayed_requests.fetch_add(1, Ordering::Relaxed);
+        agent_slot.mark_answered();
+        agent_slot.record_error();
+
+        let slot = AgentSlot::acquire(state, entry.key(), entry.value());
+        *served_by = Some(ServedBy::new(entry.key(), entry.value()));
+        request.request_id = slot.request_id.clone();
+        let (response_tx, replay_rx) = mpsc::channel(RESPONSE_CHANNEL_CAPACITY);
+        entry.value_mut().response_handlers.push(ResponseHandler::new(&slot.request_id, response_tx));
+        // A failed send means this agent is going away too, which the next wait sees
+        if let Err(e) = send_forwarded_request(entry.value(), request) {
+            warn!("Failed to replay request on agent {}: {}", entry.key(), e);
+        }
+        drop(entry);
+        *agent_slot = slot;
+        *response_rx = replay_rx;
+    }
+}
+
+// Describe an agent timeout, naming the knob operators can tune
+fn timeout_message(timeout: Duration) -> String {
+    format!(
+        "Timed out after {} seconds waiting for agent response (configure with --request-timeout or GATEWAY_TIMEOUT_SECS)",
+        timeout.as_secs()
+    )
+}
+
+// Whether the client prefers a JSON body over the plain-text/HTML default
+fn accepts_json(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get(hyper::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    !accept.contains("text/html") && (accept.contains("application/json") || accept.contains("+json"))
+}
+
+// 429 for a client over its rate limit, telling it when to retry
+fn rate_limited_response(retry_after: Duration, wants_json: bool) -> Response<Body> {
+    let mut response = direct_error_response(StatusCode::TOO_MANY_REQUESTS, "Too many requests".to_string(), wants_json);
+    response.headers_mut().insert(hyper::header::RETRY_AFTER, retry_after_value(retry_after));
+    response
+}
+
+// Copy every Set-Cookie header from the agent's response onto the client response,
+// one header per cookie since cookie values can't be safely comma-joined
+fn with_set_cookies(mut builder: axum::http::response::Builder, data: &serde_json::Value) -> axum::http::response::Builder {
+    for header in data["headers"].as_array().into_iter().flatten() {
+        let (Some(name), Some(value)) = (header[0].as_str(), header[1].as_str()) else {
+            continue;
+        };
+        if !name.eq_ignore_ascii_case("set-cookie") {
+            continue;
+        }
+        match HeaderValue::from_str(value) {
+            Ok(value) => builder = builder.header(hyper::header::SET_COOKIE, value),
+            Err(_) => warn!("Dropping invalid Set-Cookie header from agent"),
+        }
+    }
+    builder
+}
+
+// Set the local app's status code from an agent reply's data (`default` if missing or invalid), and its
+// reason phrase when the agent reported a non-standard one, e.g. "200 Awesome"; only HTTP/1 clients see it
+fn with_status_line(
+    builder: axum::http::response::Builder,
+    data: &serde_json::Value,
+    default: StatusCode,
+) -> axum::http::response::Builder {
+    let status = data["status_code"]
+        .as_u64()
+        .and_then(|code| u16::try_from(code).ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .unwrap_or(default);
+
+    let builder = builder.status(status);
+    match data["reason"].as_str().filter(|reason| Some(*reason) != status.canonical_reason()) {
+        Some(reason) => match ReasonPhrase::try_from(reason.to_string()) {
+            Ok(reason) => builder.extension(reason),
+            Err(_) => {
+                warn!("Dropping invalid reason phrase from agent");
+                builder
+            }
+        },
+        None => builder,
+    }
+}
+
+// Build an error response for the catch-all GET, as ApiResponse JSON or plain text
+fn direct_error_response(status: StatusCode, message: String, wants_json: bool) -> Response<Body> {
+    let builder = Response::builder()
+        .status(status)
+        .header("Connection", "close");
+
+    if wants_json {
+        let body = ApiResponse {
+            status: "error".to_string(),
+            message,
+            code: None,
+            data: Some(serde_json::Value::Null),
+        };
+        let Some(body) = encode_json(&body) else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        return builder
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap();
+    }
+
+    builder
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(Body::from(message))
+        .unwrap()
+}
+
+// Content types for common static assets, by lowercase path extension
+const DEFAULT_CONTENT_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+];
+
+// Content-Type for a direct GET response, from the extension of the path's last segment:
+// --content-type overrides first, then the built-in table, and text/html for anything else
+fn content_type_for_path<'a>(state: &'a AppState, path: &str) -> &'a str {
+    let extension = path
+        .rsplit('/')
+        .next()
+        .and_then(|segment| segment.rsplit_once('.'))
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    let Some(extension) = extension else {
+        return "text/html";
+    };
+    if let Some(content_type) = state.content_types.get(&extension) {
+        return content_type;
+    }
+    DEFAULT_CONTENT_TYPES
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map_or("text/html", |(_, content_type)| content_type)
+}
+
+// Content-Type for a direct response: the one the local app sent, or when it sent none (or one that
+// isn't a valid header value) a guess from the path's extension
+fn direct_content_type<'a>(state: &'a AppState, data: &'a serde_json::Value, path: &str) -> &'a str {
+    data["headers"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|header| header[0].as_str().is_some_and(|name| name.eq_ignore_ascii_case("content-type")))
+        .and_then(|header| header[1].as_str())
+        .filter(|value| HeaderValue::from_str(value).is_ok())
+        .unwrap_or_else(|| content_type_for_path(state, path))
+}
+
+// Apply the configured find/replace pairs to an HTML body, each to the result of the one before
+fn rewrite_html(body: &str, replacements: &[(String, String)]) -> String {
+    replacements
+        .iter()
+        .fold(body.to_string(), |body, (find, replace)| body.replace(find.as_str(), replace))
+}
+
+// Whether an agent reply's data carries a text/event-stream body (Server-Sent Events)
+fn is_event_stream(data: &serde_json::Value) -> bool {
+    data["headers"].as_array().into_iter().flatten().any(|header| {
+        header[0].as_str().is_some_and(|name| name.eq_ignore_ascii_case("content-type"))
+            && header[1].as_str().is_some_and(|value| {
+                value.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("text/event-stream")
+            })
+    })
+}
+
+fn unknown_purpose_message(purpose: &str) -> String {
+    format!("No agent with purpose '{}' is connected", purpose)
+}
+
+// Answer a direct request no agent can serve, with the maintenance page for browsers if one is configured
+fn no_agents_response(state: &AppState, wants_json: bool) -> Response<Body> {
+    match &state.maintenance_page {
+        Some(page) if !wants_json => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Connection", "close")
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(page.clone()))
+            .unwrap(),
+        _ => direct_error_response(StatusCode::SERVICE_UNAVAILABLE, "No agents available".to_string(), wants_json),
+    }
+}
+
+// Sequence 5: Direct GET Request Handling via Agent (Catch-All GET)
+// ---------------------------------------------------------------
+// 5.1. Capture any GET request not matching other routes (429 over the client's rate limit,
+//      503 while draining).
+// 5.2. Set up a response channel similar to the POST forward process.
+// 5.3. Identify an available agent to handle the request.
+// 5.4. Wrap and forward the GET request with appropriate headers (including the client's cookies
+//      and address) and the requested path.
+// 5.5. Wait (with the configured timeout) for the agent response.
+// 5.6. Build and return the final HTTP response to the client, with every Set-Cookie header and the
+//      local app's Content-Type, or one chosen by the path's extension when it sent none.
+// 5.7. Errors are rendered as ApiResponse JSON or plain text depending on the Accept header; when
+//      no agent is available, non-JSON clients get the --maintenance-page instead, if configured.
+async fn handle_direct_request(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    uri: axum::http::Uri,
+    Query(query): Query<Vec<(String, String)>>,
+    mut headers: HeaderMap,
+    ws: Option<WebSocketUpgrade>,
+) -> Response<Body> {
+    let path = uri.path().to_string();
+    let wants_json = accepts_json(&headers);
+    let filter = AgentFilter::from_request(&query, &headers).for_request("GET", &path);
+    if let Err(retry_after) = check_rate_limit(&state, peer) {
+        warn!("Rate limited request for {} from {}", path, peer.ip());
+        return rate_limited_response(retry_after, wants_json);
+    }
+    let Some(_in_flight) = begin_request(&state) else {
+        return direct_error_response(StatusCode::SERVICE_UNAVAILABLE, "Gateway is draining".to_string(), wants_json);
+    };
+    add_client_ip_headers(&mut headers, peer);
+
+    // Checked before upgrades too, so tunneled WebSockets can't reach unintended endpoints either
+    if let Err(reason) = validate_forward_path(&path) {
+        warn!("Rejected path {} from {}: {}", path, peer.ip(), reason);
+        return direct_error_response(StatusCode::BAD_REQUEST, format!("Invalid path: {}", reason), wants_json);
+    }
+
+    // Upgrade requests are relayed as a tunneled WebSocket instead
+    if let Some(ws) = ws {
+        return handle_tunnel_upgrade(state, &filter, path, &headers, ws, wants_json);
+    }
+
+    let started = Instant::now();
+    let mut served_by = None;
+    let response = direct_request(Arc::clone(&state), &filter, path.clone(), &headers, wants_json, &mut served_by).await;
+    let tunnel_id = served_by.as_ref().and_then(|served_by| served_by.tunnel_id.as_deref());
+    audit::record(state.audit.as_ref(), "GET", &path, tunnel_id, response.status(), started);
+    with_served_by(response, served_by.as_ref())
+}
+
+// Reject paths that could reach a different local endpoint than they appear to: dot segments
+// (also percent-encoded), empty segments from "//", and encoded slashes or backslashes that
+// the local app might decode into separators
+fn validate_forward_path(path: &str) -> Result<(), &'static str> {
+    if path.contains("//") {
+        return Err("empty path segment");
+    }
+    let lower = path.to_ascii_lowercase();
+    if path.contains('\\') || lower.contains("%2f") || lower.contains("%5c") {
+        return Err("encoded or backslash separator");
+    }
+    if lower.split('/').any(|segment| matches!(segment.replace("%2e", ".").as_str(), "." | "..")) {
+        return Err("dot segment");
+    }
+    Ok(())
+}
+
+// Headers always sent on direct requests, with the value used when the client didn't send one
+const DIRECT_HEADER_DEFAULTS: &[(&str, &str)] = &[
+    ("accept", "text/html,application/xhtml+xml"),
+    ("user-agent", "Mozilla/5.0"),
+];
+
+// Body of handle_direct_request; records the selected agent in `served_by`
+async fn direct_request(
+    state: Arc<AppState>,
+    filter: &AgentFilter,
+    path: String,
+    headers: &HeaderMap,
+    wants_json: bool,
+    served_by: &mut Option<ServedBy>,
+) -> Response<Body> {
+    info!("Received direct GET request for path: {}", path);
+
+    // The client's own Accept and User-Agent, so content negotiation works as it would without the tunnel
+    let mut forwarded_headers: Vec<(String, String)> = DIRECT_HEADER_DEFAULTS
+        .iter()
+        .map(|(name, default)| {
+            let value = headers.get(*name).and_then(|value| value.to_str().ok()).unwrap_or(default);
+            (name.to_string(), value.to_string())
+        })
+        .collect();
+    // Pass the session along; HTTP/2 clients may split cookies across several headers,
+    // which are joined into the single header HTTP/1.1 expects
+    let cookies: Vec<&str> = headers
+        .get_all(hyper::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    if !cookies.is_empty() {
+        forwarded_headers.push(("cookie".to_string(), cookies.join("; ")));
+    }
+    for name in [X_FORWARDED_FOR, X_REAL_IP] {
+        if let Some(value) = headers.get(name).and_then(|value| value.to_str().ok()) {
+            forwarded_headers.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    // One timeout and set of rewrites for the whole request, even if the config is reloaded meanwhile
+    let config = state.config();
+    let request_timeout = config.request_timeout;
+    let deadline = tokio::time::Instant::now() + request_timeout;
+    let (response_tx, mut response_rx) = mpsc::channel(RESPONSE_CHANNEL_CAPACITY);
+    // Kept until the agent replies, so it can be replayed if the agent disconnects first
+    let mut request = ForwardedRequest {
+        method: "GET".to_string(),
+        path: path.clone(),
+        body: "".to_string(),
+        binary: false,
+        headers: forwarded_headers,
+        chunked: false,
+        trailers: Vec::new(),
+        request_id: String::new(),
+    };
+    
+    // Pick the next agent in rotation
+    let mut agent_slot = None;
+    let mut send_result = Ok(());
+
+    if let Some(mut entry) = wait_for_agent(&state, filter).await.and_then(|id| state.connections.get_mut(&id)) {
+        let slot = AgentSlot::acquire(&state, entry.key(), entry.value());
+        *served_by = Some(ServedBy::new(entry.key(), entry.value()));
+        state.metrics.forwarded_requests.fetch_add(1, Ordering::Relaxed);
+        request.request_id = slot.request_id.clone();
+
+        entry.value_mut().response_handlers.push(ResponseHandler::new(&slot.request_id, response_tx.clone()));
+        send_result = send_forwarded_request(entry.value(), &request);
+        agent_slot = Some(slot);
+    }
+    // Only the agent's connection holds the sender now, so losing it closes the channel
+    drop(response_tx);
+
+    let Some(mut agent_slot) = agent_slot else {
+        state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+        if let Some(rejection) = filter.rejection(&state) {
+            return rejection.into_direct_response(wants_json);
+        }
+        return no_agents_response(&state, wants_json);
+    };
+
+    // Handle send result
+    match send_result {
+        Ok(_) => {
+            // Wait for response with the configured timeout
+            let reply = await_first_reply(
+                &state,
+                filter,
+                &mut request,
+                &mut agent_slot,
+                &mut response_rx,
+                served_by,
+                deadline,
+            )
+            .await;
+            if let Ok(Some(_)) = reply {
+                agent_slot.mark_answered();
+            }
+            match reply {
+                Ok(Some(AgentReply::Response(response))) => {
+                    info!("Received response from agent");
+                    if let Some(data) = response.get("data") {
+                        let content_type = direct_content_type(&state, data, &path);
+                        // Event streams keep their own type, whatever the path looks like, and mustn't be cached
+                        if data["streamed"].as_bool().unwrap_or(false) && is_event_stream(data) {
+                            return with_set_cookies(with_status_line(Response::builder(), data, StatusCode::OK), data)
+                                .header("Content-Type", "text/event-stream")
+                                .header("Cache-Control", "no-cache")
+                                .body(streamed_body(response_rx, agent_slot))
+                                .unwrap();
+                        }
+                        if data["streamed"].as_bool().unwrap_or(false) {
+                            return with_set_cookies(with_status_line(Response::builder(), data, StatusCode::OK), data)
+                                .header("Content-Type", content_type)
+                                .header("Connection", "close")
+                                .body(streamed_body(response_rx, agent_slot))
+                                .unwrap();
+                        }
+                        if let Some(body) = data.get("body") {
+                            if let Some(body_str) = body.as_str() {
+                                let is_html = content_type.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("text/html");
+                                let body = if is_html {
+                                    rewrite_html(body_str, &config.html_replacements)
+                                } else {
+                                    body_str.to_string()
+                                };
+                                return with_set_cookies(with_status_line(Response::builder(), data, StatusCode::OK), data)
+                                    .header("Content-Type", content_type)
+                                    .header("Connection", "close") // Add this to prevent keep-alive
+                                    .body(Body::from(body))
+                                    .unwrap();
+                            }
+                        }
+                        // If we got a response but couldn't extract the body
+                        error!("Invalid response format from agent: {:?}", data);
+                    }
+                    state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+                    agent_slot.record_error();
+                    direct_error_response(StatusCode::INTERNAL_SERVER_ERROR, "Invalid response format".to_string(), wants_json)
+                }
+                Ok(Some(AgentReply::Error(message))) => {
+                    state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+                    agent_slot.record_error();
+                    error!("Agent failed to handle request: {}", message);
+                    direct_error_response(StatusCode::BAD_GATEWAY, message, wants_json)
+                }
+                Ok(Some(_)) | Ok(None) => {
+                    state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+                    agent_slot.record_error();
+                    error!("Agent connection lost while waiting for response");
+                    direct_error_response(StatusCode::BAD_GATEWAY, "Agent connection lost".to_string(), wants_json)
+                }
+                Err(_) => {
+                    state.metrics.request_timeouts.fetch_add(1, Ordering::Relaxed);
+                    agent_slot.record_error();
+                    let message = timeout_message(request_timeout);
+                    error!("{}", message);
+                    direct_error_response(StatusCode::GATEWAY_TIMEOUT, message, wants_json)
+                }
+            }
+        }
+        Err(e) => {
+            record_send_failure(&state, &mut agent_slot, &e);
+            direct_error_response(e.status(), format!("Failed to send request: {}", e), wants_json)
+        }
+    }
+} 
+
+// Sequence 6: WebSocket Tunneling (Upgrade on the Catch-All GET)
+// ----------------------------------------------------------------
+// 6.1. Detect an `Upgrade: websocket` request on the catch-all route.
+// 6.2. Select an available agent and register a new stream ID for the client socket.
+// 6.3. Ask the agent to open the local WebSocket with a "ws_open" message.
+// 6.4. Relay client frames to the agent as "ws_frame" messages, and agent frames back to the
+//      client, until either side sends a close ("ws_close" on the agent connection).
+fn handle_tunnel_upgrade(
+    state: Arc<AppState>,
+    filter: &AgentFilter,
+    path: String,
+    headers: &HeaderMap,
+    ws: WebSocketUpgrade,
+    wants_json: bool,
+) -> Response<Body> {
+    let Some(agent) = select_agent(&state, filter)
+        .and_then(|id| state.connections.get(&id))
+        .map(|entry| (entry.key().clone(), entry.value().sender.clone()))
+    else {
+        if let Some(rejection) = filter.rejection(&state) {
+            return rejection.into_direct_response(wants_json);
+        }
+        return direct_error_response(StatusCode::SERVICE_UNAVAILABLE, "No agents available".to_string(), wants_json);
+    };
+    let (connection_id, agent_sender) = agent;
+
+    // The agent performs its own handshake with the local app, so drop WebSocket handshake headers
+    let headers: Vec<(String, String)> = forwardable_headers(headers, &state.args.strip_headers)
+        .into_iter()
+        .filter(|(name, _)| !name.starts_with("sec-websocket-") || name == "sec-websocket-protocol")
+        .collect();
+
+    // Register the stream before opening it so early frames from the agent are buffered
+    let stream_id = Uuid::new_v4().to_string();
+    let (client_tx, client_rx) = mpsc::unbounded_channel();
+    state.tunnel_streams.insert(stream_id.clone(), TunnelStream {
+        connection_id: connection_id.clone(),
+        client: client_tx,
+    });
+
+    let open = TunnelOpen { stream_id: stream_id.clone(), path: path.clone(), headers };
+    let Some(open_frame) = WebSocketMessage::with_json("ws_open", &open).and_then(|message| message.to_frame()) else {
+        state.tunnel_streams.remove(&stream_id);
+        return direct_error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode request".to_string(), wants_json);
+    };
+    match agent_sender.try_send(open_frame) {
+        Ok(()) => {}
+        Err(mpsc::error::TrySendError::Full(_)) => {
+            state.tunnel_streams.remove(&stream_id);
+            state.metrics.shed_requests.fetch_add(1, Ordering::Relaxed);
+            warn!("Shedding WebSocket upgrade for {}: agent {} queue is full", path, connection_id);
+            return direct_error_response(StatusCode::SERVICE_UNAVAILABLE, "Agent is not keeping up".to_string(), wants_json);
+        }
+        Err(e) => {
+            state.tunnel_streams.remove(&stream_id);
+            error!("Failed to send ws_open to agent: {}", e);
+            return direct_error_response(StatusCode::BAD_GATEWAY, "Agent connection lost".to_string(), wants_json);
+        }
+    }
+    info!("Tunneling WebSocket {} for {} through agent {}", stream_id, path, connection_id);
+
+    let failed_state = Arc::clone(&state);
+    let failed_stream_id = stream_id.clone();
+    let failed_sender = agent_sender.clone();
+    ws.on_failed_upgrade(move |e| {
+        warn!("Client WebSocket upgrade failed for stream {}: {}", failed_stream_id, e);
+        failed_state.tunnel_streams.remove(&failed_stream_id);
+        send_tunnel_close(&failed_sender, &failed_stream_id, None, "Client upgrade failed");
+    })
+    .on_upgrade(move |socket| relay_tunnel_stream(socket, state, stream_id, agent_sender, client_rx))
+}
+
+// Pump frames between the client socket and the agent until either side closes
+async fn relay_tunnel_stream(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    stream_id: String,
+    agent_sender: mpsc::Sender<Message>,
+    mut client_rx: mpsc::UnboundedReceiver<Message>,
+) {
+    let (mut client_sink, mut client_stream) = socket.split();
+
+    loop {
+        tokio::select! {
+            // Frames from the agent; the sender is dropped when the stream or agent goes away
+            message = client_rx.recv() => {
+                let Some(message) = message else {
+                    let _ = client_sink.send(Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "Agent disconnected".into(),
+                    }))).await;
+                    break;
+                };
+                let closing = matches!(message, Message::Close(_));
+                if client_sink.send(message).await.is_err() || closing {
+                    break;
+                }
+            }
+            // Frames from the client
+            message = client_stream.next() => {
+                let frame = match message {
+                    Some(Ok(Message::Text(text))) => TunnelFrame { stream_id: stream_id.clone(), data: text, binary: false },
+                    Some(Ok(Message::Binary(data))) => TunnelFrame { stream_id: stream_id.clone(), data: BASE64.encode(data), binary: true },
+                    Some(Ok(Message::Close(frame))) => {
+                        let (code, reason) = frame
+                            .map(|frame| (Some(frame.code), frame.reason.into_owned()))
+                            .unwrap_or((None, String::new()));
+                        send_tunnel_close(&agent_sender, &stream_id, code, &reason);
+                        break;
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) | None => {
+                        send_tunnel_close(&agent_sender, &stream_id, None, "Client disconnected");
+                        break;
+                    }
+                };
+                // Waits while the agent's queue is full, so a fast client is slowed to the agent's pace
+                let Some(frame) = WebSocketMessage::with_json("ws_frame", &frame).and_then(|message| message.to_frame()) else {
+                    continue;
+                };
+                if agent_sender.send(frame).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Flushes the close reply queued when the client initiated the close
+    let _ = client_sink.close().await;
+    state.tunnel_streams.remove(&stream_id);
+    info!("Tunneled WebSocket {} closed", stream_id);
+}
+
+// Tell the agent a tunneled stream is finished
+fn send_tunnel_close(agent_sender: &mpsc::Sender<Message>, stream_id: &str, code: Option<u16>, reason: &str) {
+    let close = TunnelClose {
+        stream_id: stream_id.to_string(),
+        code,
+        reason: reason.to_string(),
+    };
+    if let Some(frame) = WebSocketMessage::with_json("ws_close", &close).and_then(|message| message.to_frame()) {
+        let _ = agent_sender.try_send(frame);
+    }
+}
+
+// Encode a raw client body for ForwardedRequest: text is sent as-is, while multipart uploads and
+// bodies that aren't UTF-8 are base64 so their exact bytes (and multipart boundaries) survive
+fn encode_request_body(headers: &HeaderMap, body: Bytes) -> (String, bool) {
+    let multipart = headers
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim_start().to_ascii_lowercase().starts_with("multipart/"));
+    match std::str::from_utf8(&body) {
+        Ok(text) if !multipart => (text.to_string(), false),
+        _ => (BASE64.encode(&body), true),
+    }
+}
+
+// Sequence 7: Raw Forwarding (POST/PUT/PATCH/DELETE /forward/raw)
+// ----------------------------------------------------------------
+// 7.1. Accept the request body as-is, whatever its content type (429 over the rate limit,
+//      503 while draining); multipart uploads and non-UTF-8 bodies are relayed as base64.
+// 7.2. Forward it to the next agent with the client's method and headers.
+// 7.3. Wait (with the configured timeout) for the agent response.
+// 7.4. Rebuild the local app's actual response (status code, headers and body) instead of
+//      wrapping it in ApiResponse, streaming the body when the agent streams it.
+async fn handle_forward_raw_request(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    method: axum::http::Method,
+    Query(query): Query<Vec<(String, String)>>,
+    mut headers: HeaderMap,
+    body: Body,
+) -> Response<Body> {
+    if let Err(retry_after) = check_rate_limit(&state, peer) {
+        warn!("Rate limited raw {} request from {}", method, peer.ip());
+        return rate_limited_response(retry_after, accepts_json(&headers));
+    }
+    let Some(_in_flight) = begin_request(&state) else {
+        let wants_json = accepts_json(&headers);
+        return direct_error_response(StatusCode::SERVICE_UNAVAILABLE, "Gateway is draining".to_string(), wants_json);
+    };
+    let body = match read_raw_body(body, &headers, state.args.max_body_size).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    add_client_ip_headers(&mut headers, peer);
+    let started = Instant::now();
+    let filter = AgentFilter::from_request(&query, &headers).for_request(method.as_str(), "/");
+    let mut served_by = None;
+    let response = forward_raw_request(Arc::clone(&state), &filter, method.clone(), headers, body, &mut served_by).await;
+    let tunnel_id = served_by.as_ref().and_then(|served_by| served_by.tunnel_id.as_deref());
+    audit::record(state.audit.as_ref(), method.as_str(), "/", tunnel_id, response.status(), started);
+    response
+}
+
+// A /forward/raw request body, with the framing the agent reproduces for the local app
+struct RawBody {
+    bytes: Bytes,
+    // Sent without a Content-Length (chunked on HTTP/1.1)
+    chunked: bool,
+    trailers: Vec<(String, String)>,
+}
+
+// Read a /forward/raw body of at most --max-body-size bytes, keeping the trailers that follow a
+// chunked HTTP/1.1 or HTTP/2 body (axum's Bytes extractor drops them)
+async fn read_raw_body(body: Body, headers: &HeaderMap, limit: usize) -> Result<RawBody, Response<Body>> {
+    let wants_json = accepts_json(headers);
+    let collected = match Limited::new(body, limit).collect().await {
+        Ok(collected) => collected,
+        Err(e) if e.is::<LengthLimitError>() => {
+            let message = format!("Request body exceeds the {} byte limit", limit);
+            return Err(direct_error_response(StatusCode::PAYLOAD_TOO_LARGE, message, wants_json));
+        }
+        Err(e) => {
+            let message = format!("Failed to read request body: {}", e);
+            return Err(direct_error_response(StatusCode::BAD_REQUEST, message, wants_json));
+        }
+    };
+    let trailers = collected.trailers().map(trailer_pairs).unwrap_or_default();
+    let bytes = collected.to_bytes();
+    Ok(RawBody {
+        chunked: !bytes.is_empty() && !headers.contains_key(hyper::header::CONTENT_LENGTH),
+        bytes,
+        trailers,
+    })
+}
+
+// Body of handle_forward_raw_request; records the selected agent in `served_by`
+async fn forward_raw_request(
+    state: Arc<AppState>,
+    filter: &AgentFilter,
+    method: axum::http::Method,
+    headers: HeaderMap,
+    body: RawBody,
+    served_by: &mut Option<ServedBy>,
+) -> Response<Body> {
+    let wants_json = accepts_json(&headers);
+    info!("Received raw {} forward request", method);
+    let (encoded, binary) = encode_request_body(&headers, body.bytes);
+
+    // One timeout for the whole request, even if the config is reloaded meanwhile
+    let request_timeout = state.config().request_timeout;
+    let deadline = tokio::time::Instant::now() + request_timeout;
+    let (response_tx, mut response_rx) = mpsc::channel(RESPONSE_CHANNEL_CAPACITY);
+    // Kept until the agent replies, so PUT and DELETE can be replayed if the agent disconnects first
+    let mut request = ForwardedRequest {
+        method: method.to_string(),
+        path: "/".to_string(),
+        body: encoded,
+        binary,
+        headers: forwardable_headers(&headers, &state.args.strip_headers),
+        chunked: body.chunked,
+        trailers: body.trailers,
+        request_id: String::new(),
+    };
+
+    // Pick the next agent in rotation
+    let mut agent_slot = None;
+    let mut send_result = Ok(());
+
+    if let Some(mut entry) = wait_for_agent(&state, filter).await.and_then(|id| state.connections.get_mut(&id)) {
+        let slot = AgentSlot::acquire(&state, entry.key(), entry.value());
+        *served_by = Some(ServedBy::new(entry.key(), entry.value()));
+        state.metrics.forwarded_requests.fetch_add(1, Ordering::Relaxed);
+        request.request_id = slot.request_id.clone();
+
+        entry.value_mut().response_handlers.push(ResponseHandler::new(&slot.request_id, response_tx.clone()));
+        send_result = send_forwarded_request(entry.value(), &request);
+        agent_slot = Some(slot);
+    }
+    // Only the agent's connection holds the sender now, so losing it closes the channel
+    drop(response_tx);
+
+    let Some(mut agent_slot) = agent_slot else {
+        state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+        if let Some(rejection) = filter.rejection(&state) {
+            return rejection.into_direct_response(wants_json);
+        }
+        return direct_error_response(StatusCode::SERVICE_UNAVAILABLE, "No agents available".to_string(), wants_json);
+    };
+
+    if let Err(e) = send_result {
+        record_send_failure(&state, &mut agent_slot, &e);
+        return direct_error_response(e.status(), format!("Failed to send request: {}", e), wants_json);
+    }
+
+    let reply = await_first_reply(
+        &state,
+        filter,
+        &mut request,
+        &mut agent_slot,
+        &mut response_rx,
+        served_by,
+        deadline,
+    )
+    .await;
+    if let Ok(Some(_)) = reply {
+        agent_slot.mark_answered();
+    }
+    match reply {
+        Ok(Some(AgentReply::Response(response))) => {
+            info!("Received response from agent");
+            let data = &response["data"];
+            if data["streamed"].as_bool().unwrap_or(false) {
+                return raw_agent_response(data, streamed_body(response_rx, agent_slot), wants_json);
+            }
+            if let Some(body) = data["body"].as_str() {
+                let trailers = trailer_map(&data["trailers"]);
+                let body = if trailers.is_empty() {
+                    Body::from(body.to_string())
+                } else {
+                    body_with_trailers(Bytes::from(body.to_string()), trailers)
+                };
+                return raw_agent_response(data, body, wants_json);
+            }
+            error!("Invalid response format from agent: {:?}", data);
+            state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+            agent_slot.record_error();
+            direct_error_response(StatusCode::BAD_GATEWAY, "Invalid response format".to_string(), wants_json)
+        }
+        Ok(Some(AgentReply::Error(message))) => {
+            state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+            agent_slot.record_error();
+            error!("Agent failed to handle request: {}", message);
+            direct_error_response(StatusCode::BAD_GATEWAY, message, wants_json)
+        }
+        Ok(Some(_)) | Ok(None) => {
+            state.metrics.request_failures.fetch_add(1, Ordering::Relaxed);
+            agent_slot.record_error();
+            error!("Agent connection lost while waiting for response");
+            direct_error_response(StatusCode::BAD_GATEWAY, "Agent connection lost".to_string(), wants_json)
+        }
+        Err(_) => {
+            state.metrics.request_timeouts.fetch_add(1, Ordering::Relaxed);
+            agent_slot.record_error();
+            let message = timeout_message(request_timeout);
+            error!("{}", message);
+            direct_error_response(StatusCode::GATEWAY_TIMEOUT, message, wants_json)
+        }
+    }
+}
+
+// Rebuild the local app's response from an agent reply's data (status_code and headers)
+fn raw_agent_response(data: &serde_json::Value, body: Body, wants_json: bool) -> Response<Body> {
+    let mut builder = with_status_line(Response::builder(), data, StatusCode::BAD_GATEWAY);
+    let mut announces_trailers = false;
+    for header in data["headers"].as_array().into_iter().flatten() {
+        let (Some(name), Some(value)) = (header[0].as_str(), header[1].as_str()) else {
+            continue;
+        };
+        // Framing headers are recomputed for the client connection, except the Trailer header
+        // announcing trailers, without which HTTP/1.1 clients don't get them
+        let name_lower = name.to_ascii_lowercase();
+        if name_lower == "trailer" {
+            announces_trailers = true;
+        } else if HOP_BY_HOP_HEADERS.contains(&name_lower.as_str()) {
+            continue;
+        }
+        builder = builder.header(name, value);
+    }
+    if !announces_trailers {
+        let names: Vec<String> = trailer_map(&data["trailers"]).keys().map(|name| name.to_string()).collect();
+        if !names.is_empty() {
+            builder = builder.header(hyper::header::TRAILER, names.join(", "));
+        }
+    }
+
+    builder.body(body).unwrap_or_else(|e| {
+        error!("Invalid response headers from agent: {}", e);
+        direct_error_response(StatusCode::BAD_GATEWAY, "Invalid response headers".to_string(), wants_json)
+    })
+}
@@ -0,0 +1,67 @@
+use dashmap::DashMap;
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+// Per-client-IP token buckets guarding the forwarding endpoints
+pub struct RateLimiter {
+    // Tokens added per second
+    rate: f64,
+    // Bucket capacity, i.e. how many requests may arrive back to back
+    burst: f64,
+    buckets: DashMap<IpAddr, Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst: f64::from(burst),
+            buckets: DashMap::new(),
+        }
+    }
+
+    // Take a token for `ip`, or return how long until one is available
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    // Forget clients whose bucket has refilled, as they are indistinguishable from new ones
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < self.burst
+        });
+    }
+}
+
+// Parse a requests-per-second rate, which must be positive
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    let rate: f64 = value.parse().map_err(|e| format!("invalid rate '{}': {}", value, e))?;
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(format!("rate must be a positive number, got '{}'", value));
+    }
+    Ok(rate)
+}
@@ -0,0 +1,991 @@
+// End-to-end tests: the gateway runs in-process on an ephemeral port and mock agents speak the
+// WebSocket protocol to it, echoing each forwarded request back as the local app's response body.
+// They double as a reference for the agent side of the protocol.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::{net::SocketAddr, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{protocol::CloseFrame, Message},
+};
+
+// Start a gateway with the given extra flags, returning its address once it accepts connections
+async fn start_gateway(flags: &[&str]) -> SocketAddr {
+    let args = gateway::Args::parse_from(["gateway"].iter().chain(flags));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(gateway::run(args, listener));
+    addr
+}
+
+// Connect a mock agent: it handshakes with `tunnel_id`, then sends `reply(tunnel_id, request)` for
+// every forwarded request
+async fn connect_agent(addr: SocketAddr, tunnel_id: &'static str, reply: fn(&str, Value) -> Value) {
+    connect_agent_at(format!("ws://{}/ws", addr), tunnel_id, reply).await;
+}
+
+async fn connect_agent_at(ws_url: String, tunnel_id: &'static str, reply: fn(&str, Value) -> Value) {
+    let handshake = json!({ "tunnel_id": tunnel_id, "agent_version": "0.1.0" });
+    connect_agent_with(ws_url, handshake, reply).await;
+}
+
+// Connect a mock agent that sends `handshake`, for agents declaring optional capabilities
+async fn connect_agent_with(ws_url: String, handshake: Value, reply: fn(&str, Value) -> Value) {
+    let (socket, _) = connect_async(ws_url).await.unwrap();
+    let (mut write, mut read) = socket.split();
+    write.send(Message::Text(handshake.to_string())).await.unwrap();
+    let tunnel_id = handshake["tunnel_id"].as_str().unwrap().to_string();
+
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = read.next().await {
+            let Message::Text(text) = message else {
+                continue;
+            };
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message["message_type"] != "request" {
+                continue;
+            }
+            let request: Value = serde_json::from_str(message["payload"].as_str().unwrap()).unwrap();
+            let reply = reply(&tunnel_id, request);
+            if write.send(Message::Text(reply.to_string())).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+// Answer with "200 Echoed" whose body is the request as the agent received it, plus the tunnel ID
+fn echo_reply(tunnel_id: &str, mut request: Value) -> Value {
+    request["served_by"] = json!(tunnel_id);
+    let response = json!({
+        "status": "success",
+        "message": "Local server responded with status 200 OK",
+        "data": {
+            "status_code": 200,
+            "reason": "Echoed",
+            "headers": [["content-type", "application/json"]],
+            "body": request.to_string(),
+        },
+    });
+    json!({ "message_type": "response", "payload": response.to_string() })
+}
+
+// Fail like an agent whose local app is down
+fn error_reply(_tunnel_id: &str, _request: Value) -> Value {
+    json!({ "message_type": "error", "payload": "Failed to forward request to local server: Connection refused" })
+}
+
+async fn start_agent(addr: SocketAddr, tunnel_id: &'static str) {
+    connect_agent(addr, tunnel_id, echo_reply).await;
+}
+
+// Wait until `count` agents have completed their handshake
+async fn wait_for_agents(addr: SocketAddr, count: u64) {
+    wait_for_agents_at(format!("http://{}/ready", addr), count).await;
+}
+
+async fn wait_for_agents_at(ready_url: String, count: u64) {
+    for _ in 0..100 {
+        let ready: Value = reqwest::get(&ready_url).await.unwrap().json().await.unwrap();
+        if ready["data"]["ready_agents"].as_u64() == Some(count) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("{} agents did not connect", count);
+}
+
+// Start a gateway with one echoing agent
+async fn gateway_with_agent() -> SocketAddr {
+    let addr = start_gateway(&[]).await;
+    start_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web").await;
+    wait_for_agents(addr, 1).await;
+    addr
+}
+
+// The request the agent received for a /forward call, from the echoed response
+async fn forwarded_via_forward(response: reqwest::Response) -> Value {
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "success");
+    serde_json::from_str(body["data"]["data"]["body"].as_str().unwrap()).unwrap()
+}
+
+// Read past the gateway's other messages to the close frame it ends the connection with
+async fn next_close_frame<S>(socket: &mut S) -> CloseFrame<'static>
+where
+    S: futures::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Close(frame))) => return frame.unwrap(),
+            Some(Ok(_)) => continue,
+            other => panic!("connection ended without a close frame: {:?}", other),
+        }
+    }
+}
+
+fn header<'a>(request: &'a Value, name: &str) -> Option<&'a str> {
+    request["headers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|header| header[0] == name)
+        .and_then(|header| header[1].as_str())
+}
+
+#[tokio::test]
+async fn app_can_be_served_without_run() {
+    let args = gateway::Args::parse_from(["gateway"]);
+    let state = std::sync::Arc::new(gateway::AppState::new(args).unwrap());
+    let app = gateway::build_app(state).into_make_service_with_connect_info::<SocketAddr>();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    start_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web").await;
+    wait_for_agents(addr, 1).await;
+    let response = reqwest::get(format!("http://{}/page", addr)).await.unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn route_prefix_leaves_gateway_paths_to_the_tunnel() {
+    let addr = start_gateway(&["--route-prefix", "/__gateway"]).await;
+    connect_agent_at(
+        format!("ws://{}/__gateway/ws", addr),
+        "agent_7f1c2d3e-1111-4222-8333-444455556666_web",
+        echo_reply,
+    )
+    .await;
+    wait_for_agents_at(format!("http://{}/__gateway/ready", addr), 1).await;
+
+    // The local app's own /health page is no longer shadowed
+    let response = reqwest::get(format!("http://{}/health", addr)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let request: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(request["path"], "/health");
+
+    let health: Value = reqwest::get(format!("http://{}/__gateway/health", addr)).await.unwrap().json().await.unwrap();
+    assert_eq!(health["status"], "success");
+}
+
+#[tokio::test]
+async fn agents_connect_on_configured_ws_path() {
+    let addr = start_gateway(&["--ws-path", "/tunnel/connect"]).await;
+    connect_agent_at(
+        format!("ws://{}/tunnel/connect", addr),
+        "agent_7f1c2d3e-1111-4222-8333-444455556666_web",
+        echo_reply,
+    )
+    .await;
+    wait_for_agents(addr, 1).await;
+
+    let response = reqwest::get(format!("http://{}/page", addr)).await.unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn oversized_handshake_closes_the_connection() {
+    let addr = start_gateway(&["--max-handshake-size", "1024"]).await;
+    let (mut socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let handshake = json!({
+        "tunnel_id": "agent_7f1c2d3e-1111-4222-8333-444455556666_web",
+        "agent_version": "0.1.0",
+        "labels": { "padding": "x".repeat(2048) },
+    });
+    socket.send(Message::Text(handshake.to_string())).await.unwrap();
+
+    let close = next_close_frame(&mut socket).await;
+    assert_eq!(u16::from(close.code), 1009);
+    let ready: Value = reqwest::get(format!("http://{}/ready", addr)).await.unwrap().json().await.unwrap();
+    assert_eq!(ready["data"]["ready_agents"], 0);
+}
+
+#[tokio::test]
+async fn require_known_tunnel_refuses_tunnels_missing_from_the_state_file() {
+    let state_file = std::env::temp_dir().join(format!("gateway-known-tunnels-{}.json", std::process::id()));
+    let known = json!([{ "tunnel_id": "agent_7f1c2d3e-1111-4222-8333-444455556666_web", "last_seen": 0 }]);
+    std::fs::write(&state_file, known.to_string()).unwrap();
+    let addr = start_gateway(&["--state-file", state_file.to_str().unwrap(), "--require-known-tunnel"]).await;
+
+    let (mut socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let handshake = json!({ "tunnel_id": "agent_0a1b2c3d-1111-4222-8333-444455556666_web", "agent_version": "0.1.0" });
+    socket.send(Message::Text(handshake.to_string())).await.unwrap();
+    let close = next_close_frame(&mut socket).await;
+    assert_eq!(u16::from(close.code), 1008);
+
+    start_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web").await;
+    wait_for_agents(addr, 1).await;
+
+    // The handshake is written back to the state file in the background
+    for _ in 0..100 {
+        let known: Value = serde_json::from_str(&std::fs::read_to_string(&state_file).unwrap()).unwrap();
+        if known[0]["last_seen"] != 0 {
+            std::fs::remove_file(&state_file).unwrap();
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("state file was not updated");
+}
+
+#[tokio::test]
+async fn operator_disconnect_sends_a_close_code_and_reason() {
+    let addr = start_gateway(&[]).await;
+    let (mut socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let handshake = json!({ "tunnel_id": "agent_7f1c2d3e-1111-4222-8333-444455556666_web", "agent_version": "0.1.0" });
+    socket.send(Message::Text(handshake.to_string())).await.unwrap();
+    let Some(Ok(Message::Text(welcome))) = socket.next().await else {
+        panic!("no welcome message");
+    };
+    let welcome: Value = serde_json::from_str(&welcome).unwrap();
+    let welcome: Value = serde_json::from_str(welcome["payload"].as_str().unwrap()).unwrap();
+    wait_for_agents(addr, 1).await;
+
+    let url = format!("http://{}/connections/{}/disconnect", addr, welcome["connection_id"].as_str().unwrap());
+    let response = reqwest::Client::new().post(url).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let close = next_close_frame(&mut socket).await;
+    assert_eq!(u16::from(close.code), 1000);
+    assert_eq!(close.reason, "Disconnected by operator");
+}
+
+#[tokio::test]
+async fn operator_disconnect_requires_the_admin_token() {
+    let addr = start_gateway(&["--admin-token", "admin-secret"]).await;
+    start_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web").await;
+    wait_for_agents(addr, 1).await;
+    let agents: Value = reqwest::get(format!("http://{}/connections", addr)).await.unwrap().json().await.unwrap();
+    let url = format!("http://{}/connections/{}/disconnect", addr, agents["data"][0]["connection_id"].as_str().unwrap());
+
+    let response = reqwest::Client::new().post(&url).send().await.unwrap();
+    assert_eq!(response.status(), 401);
+    let ready: Value = reqwest::get(format!("http://{}/ready", addr)).await.unwrap().json().await.unwrap();
+    assert_eq!(ready["data"]["ready_agents"], 1);
+
+    let response = reqwest::Client::new().post(&url).bearer_auth("admin-secret").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn forward_round_trip() {
+    let addr = gateway_with_agent().await;
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/forward", addr))
+        .json(&json!({ "message": "Hello from client!" }))
+        .send()
+        .await
+        .unwrap();
+
+    let request = forwarded_via_forward(response).await;
+    assert_eq!(request["method"], "POST");
+    assert_eq!(request["body"], r#"{"message":"Hello from client!"}"#);
+    assert_eq!(header(&request, "x-real-ip"), Some("127.0.0.1"));
+}
+
+#[tokio::test]
+async fn direct_get_round_trip() {
+    let addr = gateway_with_agent().await;
+    let response = reqwest::get(format!("http://{}/docs/page", addr)).await.unwrap();
+
+    assert_eq!(response.status(), 200);
+    let request: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(request["method"], "GET");
+    assert_eq!(request["path"], "/docs/page");
+}
+
+#[tokio::test]
+async fn direct_get_forwards_client_accept_and_user_agent() {
+    let addr = gateway_with_agent().await;
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/page", addr))
+        .header("Accept", "application/json")
+        .header("User-Agent", "api-client/2.1")
+        .send()
+        .await
+        .unwrap();
+    let request: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(header(&request, "accept"), Some("application/json"));
+    assert_eq!(header(&request, "user-agent"), Some("api-client/2.1"));
+
+    // reqwest sends no User-Agent of its own, so the default is used
+    let response = reqwest::get(format!("http://{}/page", addr)).await.unwrap();
+    let request: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(header(&request, "user-agent"), Some("Mozilla/5.0"));
+}
+
+// Echo without a Content-Type, leaving the gateway to guess it from the path
+fn untyped_reply(tunnel_id: &str, request: Value) -> Value {
+    let mut reply = echo_reply(tunnel_id, request);
+    let mut response: Value = serde_json::from_str(reply["payload"].as_str().unwrap()).unwrap();
+    response["data"]["headers"] = json!([]);
+    reply["payload"] = json!(response.to_string());
+    reply
+}
+
+#[tokio::test]
+async fn html_replacements_rewrite_only_html_responses() {
+    let config = std::env::temp_dir().join(format!("gateway-html-replacements-{}.json", std::process::id()));
+    std::fs::write(&config, r#"{"html_replacements": [["\"GET\"", "\"REWRITTEN\""]]}"#).unwrap();
+    let addr = start_gateway(&["--config", config.to_str().unwrap()]).await;
+    connect_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web", untyped_reply).await;
+    wait_for_agents(addr, 1).await;
+
+    let html: Value = reqwest::get(format!("http://{}/docs/page", addr)).await.unwrap().json().await.unwrap();
+    let css: Value = reqwest::get(format!("http://{}/docs/site.css", addr)).await.unwrap().json().await.unwrap();
+    std::fs::remove_file(&config).unwrap();
+
+    assert_eq!(html["method"], "REWRITTEN");
+    assert_eq!(css["method"], "GET");
+}
+
+#[tokio::test]
+async fn direct_response_keeps_the_local_apps_content_type() {
+    // The echoing agent answers with application/json, whatever the path looks like
+    let addr = gateway_with_agent().await;
+    let response = reqwest::get(format!("http://{}/api/items", addr)).await.unwrap();
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let response = reqwest::get(format!("http://{}/site.css", addr)).await.unwrap();
+    assert_eq!(response.headers()["content-type"], "application/json");
+
+    // Without one from the local app, the extension decides
+    let addr = start_gateway(&[]).await;
+    connect_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web", untyped_reply).await;
+    wait_for_agents(addr, 1).await;
+    let response = reqwest::get(format!("http://{}/site.css", addr)).await.unwrap();
+    assert_eq!(response.headers()["content-type"], "text/css; charset=utf-8");
+}
+
+#[tokio::test]
+async fn served_by_header_names_the_agent() {
+    let addr = gateway_with_agent().await;
+    let connections: Value = reqwest::get(format!("http://{}/connections", addr)).await.unwrap().json().await.unwrap();
+    let expected = format!("{}; purpose=web", connections["data"][0]["connection_id"].as_str().unwrap());
+
+    let response = reqwest::get(format!("http://{}/page", addr)).await.unwrap();
+    assert_eq!(response.headers()["x-served-by"], expected.as_str());
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/forward", addr))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-served-by"], expected.as_str());
+}
+
+#[tokio::test]
+async fn forward_without_agents_reports_no_agents() {
+    let addr = start_gateway(&["--agent-wait-ms", "0"]).await;
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/forward", addr))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 503);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "error");
+    assert_eq!(body["code"], "NO_AGENTS");
+}
+
+// Keep the connection alive without ever answering, like an agent whose local app hangs
+fn heartbeat_reply(_tunnel_id: &str, _request: Value) -> Value {
+    json!({ "message_type": "heartbeat", "payload": "" })
+}
+
+#[tokio::test]
+async fn forward_timeout_is_reported_with_504() {
+    let addr = start_gateway(&["--request-timeout", "1"]).await;
+    connect_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web", heartbeat_reply).await;
+    wait_for_agents(addr, 1).await;
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/forward", addr))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 504);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "AGENT_TIMEOUT");
+}
+
+#[tokio::test]
+async fn authorization_header_reaches_agent() {
+    let addr = gateway_with_agent().await;
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/forward", addr))
+        .header("Authorization", "Bearer client-token")
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+
+    let request = forwarded_via_forward(response).await;
+    assert_eq!(header(&request, "authorization"), Some("Bearer client-token"));
+}
+
+#[tokio::test]
+async fn same_purpose_agents_alternate() {
+    let addr = start_gateway(&[]).await;
+    start_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web").await;
+    start_agent(addr, "agent_0b5e9a41-2222-4333-8444-555566667777_web").await;
+    wait_for_agents(addr, 2).await;
+
+    let mut served_by = Vec::new();
+    for _ in 0..2 {
+        let response = reqwest::get(format!("http://{}/page", addr)).await.unwrap();
+        let request: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        served_by.push(request["served_by"].as_str().unwrap().to_string());
+    }
+    assert_ne!(served_by[0], served_by[1]);
+}
+
+#[tokio::test]
+async fn resolve_names_the_next_agent_without_forwarding() {
+    let addr = start_gateway(&[]).await;
+    start_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web").await;
+    start_agent(addr, "agent_0b5e9a41-2222-4333-8444-555566667777_web").await;
+    wait_for_agents(addr, 2).await;
+
+    // Resolving twice gives the same agent, as nothing is dispatched
+    let resolve = || async {
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/resolve", addr))
+            .header("X-Tunnel-Purpose", "web")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        response.json::<Value>().await.unwrap()["data"].clone()
+    };
+    let resolved = resolve().await;
+    assert_eq!(resolve().await, resolved);
+    assert_eq!(resolved["candidates"], 2);
+
+    let response = reqwest::get(format!("http://{}/page", addr)).await.unwrap();
+    let served_by = response.headers()["x-served-by"].to_str().unwrap().to_string();
+    let request: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert!(served_by.starts_with(resolved["connection_id"].as_str().unwrap()));
+    assert_eq!(request["served_by"], resolved["tunnel_id"]);
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/resolve", addr))
+        .header("X-Tunnel-Purpose", "api")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.json::<Value>().await.unwrap()["code"], "UNKNOWN_PURPOSE");
+}
+
+#[tokio::test]
+async fn requests_outside_declared_methods_and_paths_are_refused() {
+    let addr = start_gateway(&[]).await;
+    let handshake = json!({
+        "tunnel_id": "agent_7f1c2d3e-1111-4222-8333-444455556666_web",
+        "agent_version": "0.1.0",
+        "allowed_methods": ["GET"],
+        "allowed_paths": ["/", "/api/*"],
+    });
+    connect_agent_with(format!("ws://{}/ws", addr), handshake, echo_reply).await;
+    wait_for_agents(addr, 1).await;
+
+    let response = reqwest::get(format!("http://{}/api/users?page=2", addr)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let response = reqwest::get(format!("http://{}/admin", addr)).await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    // /forward is sent to the agent as POST /
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/forward", addr))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.headers()["allow"], "GET");
+    assert_eq!(response.json::<Value>().await.unwrap()["code"], "METHOD_NOT_ALLOWED");
+}
+
+#[tokio::test]
+async fn multipart_upload_arrives_byte_identical() {
+    let addr = gateway_with_agent().await;
+    // Binary file contents that aren't valid UTF-8, inside a multipart body
+    let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.bin\"\r\n\r\n".to_vec();
+    body.extend_from_slice(&[0x00, 0xff, 0xfe, 0x80, 0x7f]);
+    body.extend_from_slice(b"\r\n--XyZ--\r\n");
+
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/forward/raw", addr))
+        .header("Content-Type", "multipart/form-data; boundary=XyZ")
+        .body(body.clone())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let request: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(request["binary"], true);
+    assert_eq!(BASE64.decode(request["body"].as_str().unwrap()).unwrap(), body);
+}
+
+// Echo the request with a gRPC-style status trailer after the body
+fn trailer_reply(tunnel_id: &str, request: Value) -> Value {
+    let mut reply = echo_reply(tunnel_id, request);
+    let mut response: Value = serde_json::from_str(reply["payload"].as_str().unwrap()).unwrap();
+    response["data"]["trailers"] = json!([["grpc-status", "0"]]);
+    reply["payload"] = json!(response.to_string());
+    reply
+}
+
+#[tokio::test]
+async fn chunked_body_and_trailers_cross_the_tunnel() {
+    let addr = start_gateway(&[]).await;
+    connect_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web", trailer_reply).await;
+    wait_for_agents(addr, 1).await;
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            b"PUT /forward/raw HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nTE: trailers\r\n\
+              Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nx-checksum: abc\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 Echoed\r\n"));
+    assert!(response.contains("\r\ngrpc-status: 0\r\n"));
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    let request: Value = serde_json::from_str(body.lines().nth(1).unwrap()).unwrap();
+    assert_eq!(request["body"], "hello");
+    assert_eq!(request["chunked"], true);
+    assert_eq!(request["trailers"], json!([["x-checksum", "abc"]]));
+}
+
+#[tokio::test]
+async fn strip_header_list_replaces_defaults() {
+    let addr = start_gateway(&["--strip-header", "x-internal,proxy-authorization"]).await;
+    start_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web").await;
+    wait_for_agents(addr, 1).await;
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/forward", addr))
+        .header("X-Internal", "secret")
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+
+    let request = forwarded_via_forward(response).await;
+    assert_eq!(header(&request, "x-internal"), None);
+    assert_eq!(header(&request, "host"), Some(addr.to_string().as_str()));
+    assert_eq!(header(&request, "content-length"), None);
+}
+
+#[tokio::test]
+async fn get_is_replayed_when_its_agent_disconnects() {
+    let addr = start_gateway(&[]).await;
+    // An agent that goes away as soon as it is sent a request, without replying
+    let (socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let (mut write, mut read) = socket.split();
+    let handshake = json!({ "tunnel_id": "agent_0b5e9a41-2222-4333-8444-555566667777_web", "agent_version": "0.1.0" });
+    write.send(Message::Text(handshake.to_string())).await.unwrap();
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = read.next().await {
+            if message.to_text().is_ok_and(|text| text.contains(r#""message_type":"request""#)) {
+                let _ = write.close().await;
+                break;
+            }
+        }
+    });
+    start_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web").await;
+    wait_for_agents(addr, 2).await;
+
+    // Round-robin sends one of the two requests to the disconnecting agent
+    for _ in 0..2 {
+        let response = reqwest::get(format!("http://{}/page", addr)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let request: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(request["served_by"], "agent_7f1c2d3e-1111-4222-8333-444455556666_web");
+    }
+    let metrics = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_replayed_requests_total 1\n"));
+}
+
+#[tokio::test]
+async fn late_response_is_counted_as_orphaned() {
+    let addr = start_gateway(&["--request-timeout", "1"]).await;
+    // An agent that answers only after the gateway has given up on the request
+    let (socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let (mut write, mut read) = socket.split();
+    let tunnel_id = "agent_7f1c2d3e-1111-4222-8333-444455556666_web";
+    write.send(Message::Text(json!({ "tunnel_id": tunnel_id, "agent_version": "0.1.0" }).to_string())).await.unwrap();
+    tokio::spawn(async move {
+        while let Some(Ok(Message::Text(text))) = read.next().await {
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message["message_type"] == "request" {
+                tokio::time::sleep(Duration::from_millis(1500)).await;
+                let request = serde_json::from_str(message["payload"].as_str().unwrap()).unwrap();
+                let _ = write.send(Message::Text(echo_reply(tunnel_id, request).to_string())).await;
+            }
+        }
+    });
+    wait_for_agents(addr, 1).await;
+
+    let response = reqwest::get(format!("http://{}/page", addr)).await.unwrap();
+    assert_eq!(response.status(), 504);
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+    let metrics = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_orphaned_responses_total 1\n"));
+}
+
+#[tokio::test]
+async fn queued_requests_count_requests_awaiting_a_response() {
+    let addr = start_gateway(&["--request-timeout", "2"]).await;
+    // An agent that reads requests but never answers them
+    let (socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let (mut write, mut read) = socket.split();
+    let handshake = json!({ "tunnel_id": "agent_7f1c2d3e-1111-4222-8333-444455556666_web", "agent_version": "0.1.0" });
+    write.send(Message::Text(handshake.to_string())).await.unwrap();
+    tokio::spawn(async move { while let Some(Ok(_)) = read.next().await {} });
+    wait_for_agents(addr, 1).await;
+
+    let request = tokio::spawn(reqwest::get(format!("http://{}/page", addr)));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let metrics = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_queued_requests 1\n"));
+    let summary: Value = reqwest::get(format!("http://{}/connections/summary", addr)).await.unwrap().json().await.unwrap();
+    assert_eq!(summary["data"]["queued_requests"], 1);
+
+    assert_eq!(request.await.unwrap().unwrap().status(), 504);
+    let metrics = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_queued_requests 0\n"));
+}
+
+#[tokio::test]
+async fn forward_is_refused_with_503_when_every_agent_is_saturated() {
+    let addr = start_gateway(&["--max-in-flight-per-agent", "1", "--request-timeout", "2"]).await;
+    // An agent that reads requests but never answers them, so the first one holds its only slot
+    let (socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let (mut write, mut read) = socket.split();
+    let handshake = json!({ "tunnel_id": "agent_7f1c2d3e-1111-4222-8333-444455556666_web", "agent_version": "0.1.0" });
+    write.send(Message::Text(handshake.to_string())).await.unwrap();
+    tokio::spawn(async move { while let Some(Ok(_)) = read.next().await {} });
+    wait_for_agents(addr, 1).await;
+
+    let forward = || reqwest::Client::new().post(format!("http://{}/forward", addr)).json(&json!({})).send();
+    let first = tokio::spawn(forward());
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let response = forward().await.unwrap();
+
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.json::<Value>().await.unwrap()["code"], "AGENTS_SATURATED");
+    let first: Value = first.await.unwrap().unwrap().json().await.unwrap();
+    assert_eq!(first["code"], "AGENT_TIMEOUT");
+}
+
+#[tokio::test]
+async fn ws_compress_gzips_large_requests_and_accepts_gzipped_replies() {
+    use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+    use std::io::{Read, Write};
+
+    let addr = start_gateway(&["--ws-compress"]).await;
+    let (socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let (mut write, mut read) = socket.split();
+    let tunnel_id = "agent_7f1c2d3e-1111-4222-8333-444455556666_web";
+    let handshake = json!({ "tunnel_id": tunnel_id, "agent_version": "0.1.0", "compression": "gzip" });
+    write.send(Message::Text(handshake.to_string())).await.unwrap();
+    let Some(Ok(Message::Text(welcome))) = read.next().await else {
+        panic!("no welcome message");
+    };
+    let welcome: Value = serde_json::from_str(&welcome).unwrap();
+    let welcome: Value = serde_json::from_str(welcome["payload"].as_str().unwrap()).unwrap();
+    assert_eq!(welcome["compression"], "gzip");
+
+    // An agent that only understands compressed requests, and compresses its own replies
+    tokio::spawn(async move {
+        while let Some(Ok(Message::Text(text))) = read.next().await {
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message["message_type"] != "request" {
+                continue;
+            }
+            assert_eq!(message["compressed"], true);
+            let gzipped = BASE64.decode(message["payload"].as_str().unwrap()).unwrap();
+            let mut payload = String::new();
+            GzDecoder::new(gzipped.as_slice()).read_to_string(&mut payload).unwrap();
+
+            let mut reply = echo_reply(tunnel_id, serde_json::from_str(&payload).unwrap());
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(reply["payload"].as_str().unwrap().as_bytes()).unwrap();
+            reply["payload"] = json!(BASE64.encode(encoder.finish().unwrap()));
+            reply["compressed"] = json!(true);
+            let _ = write.send(Message::Text(reply.to_string())).await;
+        }
+    });
+    wait_for_agents(addr, 1).await;
+
+    let body = json!({ "data": "compressible ".repeat(1000) });
+    let response = reqwest::Client::new().post(format!("http://{}/forward", addr)).json(&body).send().await.unwrap();
+    let request = forwarded_via_forward(response).await;
+    assert_eq!(request["body"], body.to_string());
+}
+
+#[tokio::test]
+async fn requests_are_shed_when_the_agent_stops_reading() {
+    let addr = start_gateway(&["--agent-queue-capacity", "1", "--request-timeout", "2"]).await;
+    // An agent that handshakes and then never reads its socket, so requests back up behind it
+    let (mut socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let handshake = json!({ "tunnel_id": "agent_7f1c2d3e-1111-4222-8333-444455556666_web", "agent_version": "0.1.0" });
+    socket.send(Message::Text(handshake.to_string())).await.unwrap();
+    wait_for_agents(addr, 1).await;
+
+    // Large enough that a few of them fill the socket buffers as well as the queue
+    let body = json!({ "data": "a".repeat(8 * 1024 * 1024) });
+    let requests: Vec<_> = (0..6)
+        .map(|_| {
+            let request = reqwest::Client::new().post(format!("http://{}/forward", addr)).json(&body).send();
+            tokio::spawn(request)
+        })
+        .collect();
+    let mut shed = 0;
+    for request in requests {
+        let response = request.await.unwrap().unwrap();
+        if response.status() == 503 {
+            assert_eq!(response.json::<Value>().await.unwrap()["code"], "AGENT_QUEUE_FULL");
+            shed += 1;
+        }
+    }
+
+    assert!(shed > 0);
+    let metrics = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains(&format!("gateway_shed_requests_total {}\n", shed)));
+    drop(socket);
+}
+
+#[tokio::test]
+async fn non_utf8_header_is_dropped() {
+    let addr = gateway_with_agent().await;
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/forward", addr))
+        .header("X-Bad", reqwest::header::HeaderValue::from_bytes(b"caf\xe9").unwrap())
+        .header("X-Good", "yes")
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+
+    let request = forwarded_via_forward(response).await;
+    assert_eq!(header(&request, "x-bad"), None);
+    assert_eq!(header(&request, "x-good"), Some("yes"));
+}
+
+#[tokio::test]
+async fn direct_response_keeps_reason_phrase() {
+    let addr = gateway_with_agent().await;
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"GET /page HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+
+    assert!(response.starts_with(b"HTTP/1.1 200 Echoed\r\n"));
+}
+
+#[tokio::test]
+async fn circuit_opens_after_consecutive_failures() {
+    let addr = start_gateway(&["--circuit-threshold", "2", "--circuit-cooldown", "60"]).await;
+    connect_agent(addr, "agent_7f1c2d3e-1111-4222-8333-444455556666_web", error_reply).await;
+    wait_for_agents(addr, 1).await;
+
+    for _ in 0..2 {
+        let response = reqwest::get(format!("http://{}/page", addr)).await.unwrap();
+        assert_eq!(response.status(), 502);
+    }
+    let response = reqwest::get(format!("http://{}/page", addr)).await.unwrap();
+    assert_eq!(response.status(), 503);
+
+    let connections: Value = reqwest::get(format!("http://{}/connections", addr)).await.unwrap().json().await.unwrap();
+    let connection = &connections["data"][0];
+    assert_eq!(connection["circuit_state"], "open");
+    assert_eq!(connection["consecutive_failures"], 2);
+    assert_eq!(connection["circuit_opened_count"], 1);
+}
+
+#[tokio::test]
+async fn event_stream_is_relayed_live_and_cancelled_on_disconnect() {
+    let addr = start_gateway(&[]).await;
+    let (socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let (mut write, mut read) = socket.split();
+    let handshake = json!({ "tunnel_id": "agent_7f1c2d3e-1111-4222-8333-444455556666_web", "agent_version": "0.1.0" });
+    write.send(Message::Text(handshake.to_string())).await.unwrap();
+    wait_for_agents(addr, 1).await;
+
+    // Open an event stream and send one event, leaving the stream open
+    let client = tokio::spawn(async move { reqwest::get(format!("http://{}/stream", addr)).await.unwrap() });
+    let request_id = loop {
+        let Some(Ok(Message::Text(text))) = read.next().await else {
+            panic!("agent connection closed");
+        };
+        let message: Value = serde_json::from_str(&text).unwrap();
+        if message["message_type"] == "request" {
+            let request: Value = serde_json::from_str(message["payload"].as_str().unwrap()).unwrap();
+            break request["request_id"].as_str().unwrap().to_string();
+        }
+    };
+    let head = json!({
+        "status": "success",
+        "message": "Local server responded with status 200 OK",
+        "data": {
+            "status_code": 200,
+            "reason": "OK",
+            "headers": [["content-type", "text/event-stream"]],
+            "streamed": true,
+        },
+    });
+    let head = json!({ "message_type": "response", "payload": head.to_string() });
+    write.send(Message::Text(head.to_string())).await.unwrap();
+    let chunk = json!({ "message_type": "response_chunk", "payload": BASE64.encode("data: tick\n\n"), "sequence": 0 });
+    write.send(Message::Text(chunk.to_string())).await.unwrap();
+
+    let mut response = client.await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    assert_eq!(response.headers()["cache-control"], "no-cache");
+    let event = tokio::time::timeout(Duration::from_secs(5), response.chunk()).await.unwrap().unwrap().unwrap();
+    assert_eq!(&event[..], b"data: tick\n\n");
+
+    // Closing the stream tells the agent to stop
+    drop(response);
+    let cancelled = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(Ok(Message::Text(text))) = read.next().await {
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message["message_type"] == "cancel" {
+                return message["payload"].as_str().unwrap().to_string();
+            }
+        }
+        panic!("agent connection closed");
+    })
+    .await
+    .unwrap();
+    assert_eq!(cancelled, request_id);
+}
+
+#[tokio::test]
+async fn concurrent_replies_reach_their_own_clients() {
+    let addr = start_gateway(&[]).await;
+    let (socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let (mut write, mut read) = socket.split();
+    let handshake = json!({ "tunnel_id": "agent_7f1c2d3e-1111-4222-8333-444455556666_web", "agent_version": "0.1.0", "concurrency": 2 });
+    write.send(Message::Text(handshake.to_string())).await.unwrap();
+    wait_for_agents(addr, 1).await;
+
+    let first = tokio::spawn(reqwest::get(format!("http://{}/first", addr)));
+    let second = tokio::spawn(reqwest::get(format!("http://{}/second", addr)));
+    let mut requests = Vec::new();
+    while requests.len() < 2 {
+        let Some(Ok(Message::Text(text))) = read.next().await else {
+            panic!("agent connection closed");
+        };
+        let message: Value = serde_json::from_str(&text).unwrap();
+        if message["message_type"] == "request" {
+            requests.push(serde_json::from_str::<Value>(message["payload"].as_str().unwrap()).unwrap());
+        }
+    }
+
+    // Answer in the opposite order to arrival, naming each request
+    for request in requests.iter().rev() {
+        let mut reply = echo_reply("agent_7f1c2d3e-1111-4222-8333-444455556666_web", request.clone());
+        reply["request_id"] = request["request_id"].clone();
+        write.send(Message::Text(reply.to_string())).await.unwrap();
+    }
+
+    let first: Value = first.await.unwrap().unwrap().json().await.unwrap();
+    let second: Value = second.await.unwrap().unwrap().json().await.unwrap();
+    assert_eq!(first["path"], "/first");
+    assert_eq!(second["path"], "/second");
+}
+
+#[tokio::test]
+async fn stalled_client_does_not_hold_up_other_requests() {
+    let addr = start_gateway(&[]).await;
+    let (socket, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    let (mut write, mut read) = socket.split();
+    let handshake = json!({
+        "tunnel_id": "agent_7f1c2d3e-1111-4222-8333-444455556666_web",
+        "agent_version": "0.1.0",
+        "concurrency": 2,
+    });
+    write.send(Message::Text(handshake.to_string())).await.unwrap();
+    wait_for_agents(addr, 1).await;
+
+    // A client that starts a large download and never reads it
+    let stalled = tokio::spawn(async move { reqwest::get(format!("http://{}/big", addr)).await.unwrap() });
+    let request_id = loop {
+        let Some(Ok(Message::Text(text))) = read.next().await else {
+            panic!("agent connection closed");
+        };
+        let message: Value = serde_json::from_str(&text).unwrap();
+        if message["message_type"] == "request" {
+            let request: Value = serde_json::from_str(message["payload"].as_str().unwrap()).unwrap();
+            break request["request_id"].as_str().unwrap().to_string();
+        }
+    };
+
+    // Far more body than the socket buffers and the request's reply buffer hold. The gateway keeps
+    // reading it all rather than waiting for the stalled client
+    let stalled_id = request_id.clone();
+    let streaming = tokio::spawn(async move {
+        let head = json!({
+            "status": "success",
+            "message": "Local server responded with status 200 OK",
+            "data": { "status_code": 200, "reason": "OK", "headers": [], "streamed": true },
+        });
+        let head = json!({ "message_type": "response", "payload": head.to_string(), "request_id": stalled_id });
+        write.send(Message::Text(head.to_string())).await.unwrap();
+        let chunk = BASE64.encode(vec![b'a'; 256 * 1024]);
+        for sequence in 0..100 {
+            let message = json!({
+                "message_type": "response_chunk",
+                "payload": chunk,
+                "sequence": sequence,
+                "request_id": stalled_id,
+            });
+            write.send(Message::Text(message.to_string())).await.unwrap();
+        }
+        write
+    });
+    let _stalled = stalled.await.unwrap();
+    let mut write = tokio::time::timeout(Duration::from_secs(10), streaming).await.unwrap().unwrap();
+
+    // The stalled request is given up on, and the next one is still served
+    let client = tokio::spawn(async move { reqwest::get(format!("http://{}/page", addr)).await.unwrap() });
+    let mut cancelled = None;
+    let request: Value = loop {
+        let Some(Ok(Message::Text(text))) = read.next().await else {
+            panic!("agent connection closed");
+        };
+        let message: Value = serde_json::from_str(&text).unwrap();
+        match message["message_type"].as_str() {
+            Some("cancel") => cancelled = message["payload"].as_str().map(str::to_string),
+            Some("request") => break serde_json::from_str(message["payload"].as_str().unwrap()).unwrap(),
+            _ => {}
+        }
+    };
+    let next_id = request["request_id"].clone();
+    let mut reply = echo_reply("agent_7f1c2d3e-1111-4222-8333-444455556666_web", request);
+    reply["request_id"] = next_id;
+    write.send(Message::Text(reply.to_string())).await.unwrap();
+
+    let response = tokio::time::timeout(Duration::from_secs(5), client).await.unwrap().unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(cancelled, Some(request_id));
+}